@@ -0,0 +1,118 @@
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+
+/// At most this many announcements are made at once, the rest only being counted.
+const MAX_BATCH: usize = 3;
+
+/// Collects announcements of significant events for `--announce`, to be printed as plain
+/// lines rather than repainting the screen. Announcements are made at most once per
+/// interval, and repeats of pending or just made announcements are dropped.
+pub struct Announcer {
+    interval: Duration,
+    last: Option<Instant>,
+    pending: Vec<String>,
+    /// The announcements made last time.
+    said: Vec<String>,
+    /// Programs whose end was announced already.
+    finished: HashSet<usize>,
+}
+
+impl Announcer {
+    pub fn new(interval: Duration) -> Self {
+        Announcer {
+            interval,
+            last: None,
+            pending: vec![],
+            said: vec![],
+            finished: HashSet::new(),
+        }
+    }
+
+    /// Announce how a program ended, once.
+    pub fn finished(&mut self, key: usize, message: String) {
+        if self.finished.insert(key) {
+            self.announce(message);
+        }
+    }
+
+    pub fn announce(&mut self, message: String) {
+        if !self.pending.contains(&message) && !self.said.contains(&message) {
+            self.pending.push(message);
+        }
+    }
+
+    /// The lines to print now, if any are pending and the interval passed, or if forced.
+    pub fn flush(&mut self, force: bool) -> Vec<String> {
+        let now = Instant::now();
+        let due = match self.last {
+            Some(last) => now >= last + self.interval,
+            None => true,
+        };
+        if self.pending.is_empty() || !(due || force) {
+            return vec![];
+        }
+
+        self.last = Some(now);
+        self.said = std::mem::take(&mut self.pending);
+
+        let mut lines: Vec<String> = self.said.iter().take(MAX_BATCH).cloned().collect();
+        if self.said.len() > MAX_BATCH {
+            lines.push(format!("and {} more", self.said.len() - MAX_BATCH));
+        }
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn throttling() {
+        let mut announcer = Announcer::new(Duration::from_secs(3600));
+        announcer.announce("a".to_owned());
+        assert_eq!(announcer.flush(false), ["a"]);
+
+        announcer.announce("b".to_owned());
+        assert!(announcer.flush(false).is_empty());
+        assert_eq!(announcer.flush(true), ["b"]);
+        assert!(announcer.flush(true).is_empty());
+
+        let mut announcer = Announcer::new(Duration::from_secs(0));
+        announcer.announce("a".to_owned());
+        assert_eq!(announcer.flush(false), ["a"]);
+        announcer.announce("b".to_owned());
+        assert_eq!(announcer.flush(false), ["b"]);
+    }
+
+    #[test]
+    fn dedup() {
+        let mut announcer = Announcer::new(Duration::from_secs(0));
+        announcer.announce("a".to_owned());
+        announcer.announce("a".to_owned());
+        assert_eq!(announcer.flush(false), ["a"]);
+
+        // Repeats of what was just said are dropped, older ones are not.
+        announcer.announce("a".to_owned());
+        assert!(announcer.flush(false).is_empty());
+        announcer.announce("b".to_owned());
+        assert_eq!(announcer.flush(false), ["b"]);
+        announcer.announce("a".to_owned());
+        assert_eq!(announcer.flush(false), ["a"]);
+
+        announcer.finished(1, "done".to_owned());
+        assert_eq!(announcer.flush(false), ["done"]);
+        announcer.announce("x".to_owned());
+        announcer.finished(1, "done".to_owned());
+        assert_eq!(announcer.flush(false), ["x"]);
+    }
+
+    #[test]
+    fn batches() {
+        let mut announcer = Announcer::new(Duration::from_secs(0));
+        for message in ["a", "b", "c", "d", "e"] {
+            announcer.announce(message.to_owned());
+        }
+        assert_eq!(announcer.flush(false), ["a", "b", "c", "and 2 more"]);
+    }
+}
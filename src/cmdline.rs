@@ -1,11 +1,26 @@
+use structopt::clap::{self, AppSettings};
 use structopt::StructOpt;
 
 #[derive(StructOpt, Debug)]
+#[structopt(
+    setting = AppSettings::ArgsNegateSubcommands,
+    setting = AppSettings::AllowLeadingHyphen
+)]
 pub struct Opt {
-    // Programs to execute, instead of reading 'stdin'. These are separated by '-/-'.
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
+
+    // Programs to execute, instead of reading 'stdin', as in 'foldity make -j8 -/- ls -l'. These
+    // are separated by '-/-', where '-//-' stands for a literal '-/-' argument, and so on.
     pub programs: Vec<String>,
 
-    // Regex to match context beginning
+    // A single program to execute exactly as given after '--', as in 'foldity -- cmd args...',
+    // without any '-/-' splitting or unescaping.
+    #[structopt(last = true)]
+    pub exec: Vec<String>,
+
+    // Regex to match context beginning. With several captures, the one named 'M' is the title,
+    // and other named captures are kept as metadata of the fold, e.g. (?P<shard>\d+).
     #[structopt(short = "-s", long = "match-begin")]
     pub match_start: Vec<String>,
 
@@ -18,10 +33,42 @@ pub struct Opt {
     pub match_pairs_file: Option<String>,
 
     // Instead of stdin, describe shell programs to from given input file
-    // a shell script per line. If '-' then reads shell scripts from stdin.
+    // a shell script per line. If '-' then reads shell scripts from stdin, starting each
+    // one as soon as it arrives, until stdin is closed.
+    //
+    // Lines starting with '#@' hold 'key: value' settings for the program on the next
+    // line: 'nice: N', 'ionice: CLASS[:LEVEL]' (realtime, best-effort, idle), the
+    // cgroup limits 'memory: SIZE' and 'cpu: PERCENT%', applied via 'systemd-run', and
+    // 'delay: DURATION' for starting it later, 'cpus: LIST' for its CPU affinity, e.g. 0-3,8,
+    // 'env: NAME=VALUE' for setting an environment variable, and 'label: NAME'. In the
+    // command, 'env' values, 'cpus' and 'expect' responses, '{index}' stands for the program's
+    // position among all programs, from 0, and '{label}' for its label, which defaults to the
+    // index.
+    //
+    // 'expect: REGEX => RESPONSE' answers prompts of semi-interactive programs: when output
+    // matches the regex, the response is written to the program's stdin, followed by a line
+    // ending. Prompts that do not end their line, such as 'Proceed? [y/N] ', are answered
+    // once they arrive. Programs with such settings get a stdin that stays open rather than
+    // an empty one.
     #[structopt(short = "-p", long = "programs-file")]
     pub programs_file: Option<String>,
 
+    // Start programs spaced apart by this long, e.g. '2s', rather than all at once. Programs
+    // can also be delayed individually with a 'delay: DURATION' setting in the programs file.
+    #[structopt(long = "stagger", parse(try_from_str = crate::util::parse_duration))]
+    pub stagger: Option<std::time::Duration>,
+
+    // Kill all programs once this much time has passed since the start, e.g. '30m', and exit
+    // with an error after the usual final report.
+    #[structopt(long = "total-timeout", parse(try_from_str = crate::util::parse_duration))]
+    pub total_timeout: Option<std::time::Duration>,
+
+    // When the output is not a terminal, it is held back until the programs are done. Print
+    // a line to stderr this often meanwhile, e.g. '60s', for CI systems that watch for
+    // inactivity.
+    #[structopt(long = "heartbeat", parse(try_from_str = crate::util::parse_duration))]
+    pub heartbeat: Option<std::time::Duration>,
+
     // Use provided shell executable rather than the default `/bin/sh`.
     #[structopt(short = "-C", long = "shell")]
     pub shell: Option<String>,
@@ -40,6 +87,271 @@ pub struct Opt {
     #[structopt(short = "-D", long = "interline-delay", default_value = "0")]
     pub interline_delay: usize,
 
+    // How output bytes that are not valid UTF-8 are shown: 'replace' them with U+FFFD (the
+    // default), or 'escape' them as \xNN.
+    #[structopt(long = "invalid-utf8", default_value = "replace")]
+    pub invalid_utf8: crate::util::InvalidUtf8,
+
+    // Keep at most this many bytes of each line, marking longer lines as truncated, so that
+    // minified files and encoded blobs do not weigh on memory and redraws. 0, the default, for
+    // no limit.
+    #[structopt(long = "max-line-length", default_value = "0")]
+    pub max_line_length: usize,
+
+    // Append lines cut by `--max-line-length` to the given file in full, each preceded by a
+    // header naming the program and the line number.
+    #[structopt(long = "long-lines-file")]
+    pub long_lines_file: Option<String>,
+
+    // Replace common credentials, such as AWS keys and bearer tokens, with [REDACTED] in
+    // everything shown, saved or dumped.
+    #[structopt(long = "redact")]
+    pub redact: bool,
+
+    // Also redact matches of the given regex, or only its group named 'secret' if it has one.
+    // May be given several times. Implies --redact.
+    #[structopt(long = "redact-pattern", number_of_values = 1)]
+    pub redact_patterns: Vec<regex::Regex>,
+
+    // Rewrite lines before matching and showing them, as s/REGEX/REPLACEMENT/ with an optional
+    // 'g' flag, like sed. May be given several times, applying in order. The final dump of
+    // the output keeps the lines as they were.
+    #[structopt(long = "rewrite", number_of_values = 1)]
+    pub rewrite: Vec<crate::rewrite::Rewrite>,
+
+    // In the final dump of the output, drop carriage returns, keeping what a terminal would
+    // show for lines that were overwritten by them.
+    #[structopt(long = "normalize-eol")]
+    pub normalize_eol: bool,
+
+    // In the final dump of the output, remove escape sequences and control characters.
+    #[structopt(long = "strip-control")]
+    pub strip_control: bool,
+
+    // For screen readers and braille displays: rather than drawing, print a line on
+    // significant events, such as folds starting and ending, errors, and programs finishing,
+    // at most once per given interval.
+    #[structopt(long = "announce", parse(try_from_str = crate::util::parse_duration))]
+    pub announce: Option<std::time::Duration>,
+
+    // Show the amount of lines hidden behind each closed fold next to its title.
+    #[structopt(short = "-n", long = "line-counts")]
+    pub line_counts: bool,
+
+    // Show a timeline under the title of each program, in which each top-level fold takes
+    // room in proportion to its duration: blue while running, green once done, and red if the
+    // program failed in it, or after it when it is the last. Time outside of folds, or before
+    // starting, is gray.
+    #[structopt(long = "timeline")]
+    pub timeline: bool,
+
+    // Save the session to the given file when done, for later inspection with `open`. The
+    // file is JSON, in which each line and fold records the program it came from, its line
+    // number in the program's output, and when it arrived, and lines are numbered across all
+    // programs in order of arrival, so that the interleaving of the programs can be
+    // reconstructed.
+    #[structopt(long = "save")]
+    pub save: Option<String>,
+
+    // Save only the folds matching a filter, such as 'outcome=failed && duration>30s'. Joins
+    // conditions with && and ||, comparing with =, !=, <, <=, >, >= or ~ (contains). Fields
+    // are program, label, title, outcome (ok, failed, killed or running), duration, and the
+    // names of metadata captures.
+    #[structopt(long = "save-filter", requires = "save")]
+    pub save_filter: Option<crate::filter::Filter>,
+
+    // Show programs that ended the same way after going through the same folds as a single
+    // line, e.g. when running the same command on many hosts, so that only the divergent
+    // ones take up the screen.
+    #[structopt(long = "aggregate")]
+    pub aggregate: bool,
+
+    // Keep writing snapshots of the session to the given directory while running, in the
+    // format of `--save`, so that what a run had reached can be looked into with `open` even
+    // after a crash. Snapshots are written periodically and soon after folds start or end.
+    #[structopt(long = "spool")]
+    pub spool: Option<String>,
+
+    // Time between snapshots written with `--spool` when nothing significant happens.
+    #[structopt(long = "spool-interval", default_value = "30s", parse(try_from_str = crate::util::parse_duration))]
+    pub spool_interval: std::time::Duration,
+
+    // How many of the latest snapshots written with `--spool` to keep.
+    #[structopt(long = "spool-keep", default_value = "5")]
+    pub spool_keep: usize,
+
+    // Compare with a session saved with `--save` by a previous run, collapsing folds whose
+    // content did not change since.
+    #[structopt(long = "compare-with")]
+    pub compare_with: Option<String>,
+
+    // Let 'foldity view ADDRESS' show the same session read-only from another terminal, where
+    // ADDRESS is unix:/path/of/socket.
+    #[structopt(long = "mirror")]
+    pub mirror: Option<crate::remote::Address>,
+
+    // Keep running in the background, to be attached to later with `attach NAME`.
+    #[structopt(long = "daemonize", requires = "session")]
+    pub daemonize: bool,
+
+    // Name of the background session.
+    #[structopt(long = "session")]
+    pub session: Option<String>,
+
+    // Terminal capabilities to assume: auto, color, color-ascii, mono or ascii. By default
+    // these are detected from the environment.
+    #[structopt(long = "profile")]
+    pub profile: Option<crate::profile::Profile>,
+
+    // How times are shown: elapsed since the start (the default), or the local time on a
+    // 24h or 12h clock.
+    #[structopt(long = "time-format", default_value = "elapsed")]
+    pub time_format: crate::util::Clock,
+
+    // Index the output as it arrives, so that filtering stays fast on large sessions at the
+    // cost of memory.
+    #[structopt(long = "index")]
+    pub index: bool,
+
+    // On screens narrower than this, indent each level of nesting by 2 columns rather than 4.
+    #[structopt(long = "narrow-width", default_value = "80")]
+    pub narrow_width: usize,
+
+    // Percentage of the screen width that indentation may take at most. Deeper folds share the
+    // last column, and their titles show their depth as a number.
+    #[structopt(long = "max-indent", default_value = "40")]
+    pub max_indent: usize,
+
+    // Lines matching this regex count as errors. Programs that printed one within the last
+    // --error-window get more of the screen when there is not enough room for all.
+    #[structopt(long = "error-regex", default_value = r"(?i)\b(error|fatal|failed)\b")]
+    pub error_regex: regex::Regex,
+
+    // How long a program counts as having recent errors after printing one, e.g. '30s'.
+    #[structopt(
+        long = "error-window",
+        default_value = "10s",
+        parse(try_from_str = crate::util::parse_duration)
+    )]
+    pub error_window: std::time::Duration,
+
+    // How many times more rows a program with recent errors gets than a quiet one, when not
+    // all programs fit. 1 treats all programs alike.
+    #[structopt(long = "error-weight", default_value = "3")]
+    pub error_weight: u64,
+
+    // How to shorten lines that do not fit, as KIND=TRUNCATION, where KIND is one of
+    // program, title or text, and TRUNCATION is one of end (the default), middle or path.
+    // May be given once per kind.
+    #[structopt(long = "truncate", number_of_values = 1)]
+    pub truncate: Vec<crate::display::TruncationRule>,
+
+    // When done, mention it if a newer release is available.
+    #[structopt(long = "check-update")]
+    pub check_update: bool,
+
     #[structopt(short = "-d", long = "debug")]
     pub debug: bool,
 }
+
+#[derive(StructOpt, Debug)]
+pub enum Command {
+    // Navigate a session previously saved with `--save`.
+    #[structopt(name = "open")]
+    Open { file: String },
+
+    // Play a session saved with `--save` back in its original pace. Space pauses, '.' steps a
+    // line, Left and Right jump between fold boundaries, and 0 to 9 seek to that tenth of it.
+    #[structopt(name = "play")]
+    Play { file: String },
+
+    // View a session running in the background. Ctrl+\ detaches, leaving it running.
+    #[structopt(name = "attach")]
+    Attach { name: String },
+
+    // View a session shown elsewhere with '--mirror ADDRESS', without affecting it. Ctrl+\
+    // detaches.
+    #[structopt(name = "view")]
+    View { address: crate::remote::Address },
+
+    // Run a single command, e.g. 'foldity wrap make -j8', with its output folded when on a
+    // terminal and passed through untouched otherwise. Stdin, the exit code and signals are
+    // those of the command, so that it can stand in for it in a shell alias.
+    #[structopt(name = "wrap", setting = AppSettings::TrailingVarArg)]
+    Wrap {
+        #[structopt(required = true, allow_hyphen_values = true)]
+        command: Vec<String>,
+    },
+
+    // Replace this executable with the binary of the latest release, using 'curl', once
+    // 'sha256sum' finds that it matches its published checksum.
+    #[structopt(name = "self-update")]
+    SelfUpdate,
+}
+
+impl Opt {
+    /// Like `from_iter_safe`, but hyphens are only allowed past the first word of the
+    /// programs, so that a mistyped option is not taken for the name of a program.
+    pub fn from_iter_checked<I>(args: I) -> Result<Self, clap::Error>
+    where
+        I: IntoIterator,
+        I::Item: Into<std::ffi::OsString> + Clone,
+    {
+        let opt = Self::from_iter_safe(args)?;
+        match opt.programs.first() {
+            Some(first) if first.starts_with('-') => Err(clap::Error::with_description(
+                &format!(
+                    "Found argument '{}' which wasn't expected, or isn't valid in this context",
+                    first
+                ),
+                clap::ErrorKind::UnknownArgument,
+            )),
+            _ => Ok(opt),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Opt {
+        Opt::from_iter_checked(std::iter::once("foldity").chain(args.iter().copied())).unwrap()
+    }
+
+    #[test]
+    fn exec_after_double_dash() {
+        let opt = parse(&["--", "find", "/", "-/-", "-//-"]);
+        assert!(opt.programs.is_empty());
+        assert_eq!(opt.exec, vec!["find", "/", "-/-", "-//-"]);
+    }
+
+    #[test]
+    fn programs_before_double_dash() {
+        let opt = parse(&["make", "-j8", "-/-", "ls", "-//-"]);
+        assert_eq!(opt.programs, vec!["make", "-j8", "-/-", "ls", "-//-"]);
+        assert!(opt.exec.is_empty());
+    }
+
+    #[test]
+    fn options_before_programs() {
+        let opt = parse(&["--error-weight", "2", "make", "-j8"]);
+        assert_eq!(opt.error_weight, 2);
+        assert_eq!(opt.programs, vec!["make", "-j8"]);
+    }
+
+    #[test]
+    fn mistyped_options() {
+        let args = ["foldity", "--error-wieght", "2", "make"];
+        assert!(Opt::from_iter_checked(args.iter()).is_err());
+        let args = ["foldity", "-Z", "make"];
+        assert!(Opt::from_iter_checked(args.iter()).is_err());
+    }
+
+    #[test]
+    fn subcommands_still_apply() {
+        let opt = parse(&["wrap", "make", "-j8"]);
+        assert!(opt.programs.is_empty());
+        assert!(matches!(opt.command, Some(Command::Wrap { .. })));
+    }
+}
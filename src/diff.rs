@@ -0,0 +1,206 @@
+/// A step in the edit script turning one sequence of lines into another.
+#[derive(Clone, Copy, PartialEq)]
+enum Op {
+    Equal(usize),
+    Delete(usize),
+    Insert(usize),
+}
+
+/// Shortest edit script between `a` and `b`, using Myers' algorithm.
+fn edit_script(a: &[&str], b: &[&str]) -> Vec<Op> {
+    let n = a.len() as isize;
+    let m = b.len() as isize;
+    let max = n + m;
+    let offset = max + 1;
+    let mut v = vec![0isize; 2 * max as usize + 3];
+    let mut trace = vec![];
+
+    'outer: for d in 0..=max {
+        trace.push(v.clone());
+        for k in (-d..=d).step_by(2) {
+            let idx = (k + offset) as usize;
+            let mut x = if k == -d || (k != d && v[idx - 1] < v[idx + 1]) {
+                v[idx + 1]
+            } else {
+                v[idx - 1] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[idx] = x;
+            if x >= n && y >= m {
+                break 'outer;
+            }
+        }
+    }
+
+    let mut ops = vec![];
+    let (mut x, mut y) = (n, m);
+    for (d, v) in trace.iter().enumerate().rev() {
+        let d = d as isize;
+        let k = x - y;
+        let idx = (k + offset) as usize;
+        let prev_k = if k == -d || (k != d && v[idx - 1] < v[idx + 1]) {
+            k + 1
+        } else {
+            k - 1
+        };
+        let prev_x = v[(prev_k + offset) as usize];
+        let prev_y = prev_x - prev_k;
+
+        while x > prev_x && y > prev_y {
+            ops.push(Op::Equal((x - 1) as usize));
+            x -= 1;
+            y -= 1;
+        }
+
+        if d > 0 {
+            if x == prev_x {
+                ops.push(Op::Insert((y - 1) as usize));
+            } else {
+                ops.push(Op::Delete((x - 1) as usize));
+            }
+        }
+
+        x = prev_x;
+        y = prev_y;
+    }
+
+    ops.reverse();
+    ops
+}
+
+/// A line of unified diff output.
+pub enum DiffLine {
+    Hunk(String),
+    Context(String),
+    Removed(String),
+    Added(String),
+}
+
+/// Render the differences between `a` and `b` as unified diff hunks, each change surrounded by
+/// up to `context` unchanged lines.
+pub fn unified(a: &[&str], b: &[&str], context: usize) -> Vec<DiffLine> {
+    let ops = edit_script(a, b);
+    let is_change = |op: &Op| !matches!(op, Op::Equal(_));
+
+    // Positions in `a` and `b` before each op, for the hunk headers.
+    let mut positions = Vec::with_capacity(ops.len() + 1);
+    let (mut a_pos, mut b_pos) = (0, 0);
+    for op in &ops {
+        positions.push((a_pos, b_pos));
+        match op {
+            Op::Equal(_) => {
+                a_pos += 1;
+                b_pos += 1;
+            }
+            Op::Delete(_) => a_pos += 1,
+            Op::Insert(_) => b_pos += 1,
+        }
+    }
+    positions.push((a_pos, b_pos));
+
+    let mut output = vec![];
+    let mut idx = 0;
+
+    while let Some(first) = ops[idx..].iter().position(is_change) {
+        let first = idx + first;
+        let start = first.saturating_sub(context).max(idx);
+
+        let mut end = first + 1;
+        while let Some(next) = ops[end..].iter().position(is_change) {
+            if next > context * 2 {
+                break;
+            }
+            end += next + 1;
+        }
+        let end = (end + context).min(ops.len());
+
+        let (a_start, b_start) = positions[start];
+        let (a_end, b_end) = positions[end];
+        output.push(DiffLine::Hunk(format!(
+            "@@ -{} +{} @@",
+            hunk_range(a_start, a_end),
+            hunk_range(b_start, b_end)
+        )));
+
+        for op in &ops[start..end] {
+            output.push(match *op {
+                Op::Equal(i) => DiffLine::Context(a[i].to_owned()),
+                Op::Delete(i) => DiffLine::Removed(a[i].to_owned()),
+                Op::Insert(j) => DiffLine::Added(b[j].to_owned()),
+            });
+        }
+
+        idx = end;
+    }
+
+    output
+}
+
+/// A range of lines, given by index from 0, as in a hunk header. An empty range is given by
+/// the line it follows, which is 0 at the start.
+fn hunk_range(start: usize, end: usize) -> String {
+    match end - start {
+        0 => format!("{},0", start),
+        len => format!("{},{}", start + 1, len),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn diff(a: &str, b: &str, context: usize) -> Vec<String> {
+        let a: Vec<_> = a.split_whitespace().collect();
+        let b: Vec<_> = b.split_whitespace().collect();
+        unified(&a, &b, context)
+            .into_iter()
+            .map(|line| match line {
+                DiffLine::Hunk(s) => s,
+                DiffLine::Context(s) => format!(" {}", s),
+                DiffLine::Removed(s) => format!("-{}", s),
+                DiffLine::Added(s) => format!("+{}", s),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn identical() {
+        assert!(diff("a b c", "a b c", 3).is_empty());
+        assert!(diff("", "", 3).is_empty());
+    }
+
+    #[test]
+    fn changes_with_context() {
+        assert_eq!(
+            diff("a b c d e", "a b x d e", 1),
+            vec!["@@ -2,3 +2,3 @@", " b", "-c", "+x", " d"]
+        );
+        assert_eq!(diff("", "a", 3), vec!["@@ -0,0 +1,1 @@", "+a"]);
+        assert_eq!(diff("a", "", 3), vec!["@@ -1,1 +0,0 @@", "-a"]);
+        // Without context, the empty side is given by the line it follows.
+        assert_eq!(diff("a b", "a x b", 0), vec!["@@ -1,0 +2,1 @@", "+x"]);
+        assert_eq!(diff("a x b", "a b", 0), vec!["@@ -2,1 +1,0 @@", "-x"]);
+    }
+
+    #[test]
+    fn separate_hunks() {
+        assert_eq!(
+            diff("a b c d e f g h", "x b c d e f g y", 1),
+            vec![
+                "@@ -1,2 +1,2 @@",
+                "-a",
+                "+x",
+                " b",
+                "@@ -7,2 +7,2 @@",
+                " g",
+                "-h",
+                "+y",
+            ]
+        );
+        assert_eq!(diff("a b c d e f g h", "x b c d e f g y", 3).len(), 11);
+    }
+}
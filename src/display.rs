@@ -1,5 +1,209 @@
-use super::Output;
+use super::util::Format;
+use super::{Encapsulation, Output};
 use smallvec::SmallVec;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// Knobs affecting how programs are laid out, derived from the command line.
+#[derive(Default, Clone)]
+pub struct DisplayOptions {
+    /// Append the amount of hidden lines to titles of closed folds.
+    pub line_counts: bool,
+    /// Show a timeline of the top-level folds under the title of each program.
+    pub timeline: bool,
+    /// When set, only lines containing this text are shown, along with the folds enclosing them.
+    pub filter: Option<String>,
+    /// Restrict drawing to ASCII characters.
+    pub ascii: bool,
+    /// How lines too long for the screen are shortened, per kind of line.
+    pub truncation: TruncationRules,
+    pub format: Format,
+    /// When to indent nesting by less.
+    pub indentation: Indentation,
+    pub width_cache: RefCell<WidthCache>,
+    /// Hashes of the folds of a previous run. Folds that ended the same way are collapsed.
+    pub previous_folds: Option<Arc<HashSet<u64>>>,
+}
+
+impl DisplayOptions {
+    pub fn glyphs(&self) -> &'static Glyphs {
+        if self.ascii {
+            &ASCII_GLYPHS
+        } else {
+            &UNICODE_GLYPHS
+        }
+    }
+
+    /// Whether the fold ended with the same content as it had in the previous run.
+    pub fn same_as_last_run(&self, encapsulation: &Encapsulation) -> bool {
+        match (&self.previous_folds, encapsulation.hash) {
+            (Some(previous), Some(hash)) => previous.contains(&hash),
+            _ => false,
+        }
+    }
+}
+
+/// Columns by which each level of nesting is indented, unless the screen is too narrow for it.
+const INDENT: usize = 4;
+
+/// How nesting is indented, depending on the width of the screen.
+#[derive(Clone, Copy, Debug)]
+pub struct Indentation {
+    /// Below this width, each level is indented by half as much.
+    pub narrow_width: usize,
+    /// Indentation takes at most this percentage of the width. Levels deeper than that share
+    /// the same column, and their titles show their depth instead.
+    pub max_percent: usize,
+}
+
+impl Default for Indentation {
+    fn default() -> Self {
+        Indentation {
+            narrow_width: 80,
+            max_percent: 40,
+        }
+    }
+}
+
+/// Characters used for drawing the structure around the text.
+pub struct Glyphs {
+    pub vertical: &'static str,
+    pub title: &'static str,
+    pub separator: &'static str,
+    pub horizontal: &'static str,
+    pub top_left: &'static str,
+    pub top_right: &'static str,
+    pub bottom_left: &'static str,
+    pub bottom_right: &'static str,
+    /// Timeline cells, telling the phases apart without colors as well: idle, running, done
+    /// and failed.
+    pub phases: [&'static str; 4],
+}
+
+const UNICODE_GLYPHS: Glyphs = Glyphs {
+    vertical: "⫼ ",
+    title: "└── ",
+    separator: "│",
+    horizontal: "─",
+    top_left: "┌",
+    top_right: "┐",
+    bottom_left: "└",
+    bottom_right: "┘",
+    phases: ["░", "▒", "█", "▓"],
+};
+
+const ASCII_GLYPHS: Glyphs = Glyphs {
+    vertical: "| ",
+    title: "`-- ",
+    separator: "|",
+    horizontal: "-",
+    top_left: "+",
+    top_right: "+",
+    bottom_left: "+",
+    bottom_right: "+",
+    phases: [".", ">", "#", "X"],
+};
+
+/// How a line that does not fit on the screen gets shortened.
+#[derive(Default, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Truncation {
+    /// Chop the end of the line.
+    #[default]
+    End,
+    /// Keep both ends of the line, eliding its middle.
+    Middle,
+    /// Drop the leading components of paths in the line, e.g. `.../src/foo.rs`, and chop
+    /// the end if that is not enough.
+    Path,
+}
+
+impl FromStr for Truncation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "end" => Truncation::End,
+            "middle" => Truncation::Middle,
+            "path" => Truncation::Path,
+            _ => {
+                return Err(format!(
+                    "unknown truncation {}, expected one of: end, middle, path",
+                    s
+                ))
+            }
+        })
+    }
+}
+
+/// Truncation strategy for each kind of line that carries text.
+#[derive(Default, Clone, Copy, Debug)]
+pub struct TruncationRules {
+    pub program: Truncation,
+    pub title: Truncation,
+    pub text: Truncation,
+}
+
+impl TruncationRules {
+    pub fn set(&mut self, rule: &TruncationRule) {
+        match rule.kind {
+            RuleKind::Program => self.program = rule.truncation,
+            RuleKind::Title => self.title = rule.truncation,
+            RuleKind::Text => self.text = rule.truncation,
+        }
+    }
+
+    fn get(&self, kind: &DisplayKind) -> Truncation {
+        match kind {
+            DisplayKind::ProgramTitle => self.program,
+            DisplayKind::Title(_) => self.title,
+            DisplayKind::Text(_) => self.text,
+            DisplayKind::MiddleTextCut(_) | DisplayKind::WholeScreenCut => Truncation::End,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum RuleKind {
+    Program,
+    Title,
+    Text,
+}
+
+/// A `KIND=TRUNCATION` command line setting, e.g. `program=middle`.
+#[derive(Clone, Copy, Debug)]
+pub struct TruncationRule {
+    kind: RuleKind,
+    truncation: Truncation,
+}
+
+impl FromStr for TruncationRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let pos = s
+            .find('=')
+            .ok_or_else(|| format!("expected KIND=TRUNCATION, got {}", s))?;
+        let kind = match &s[..pos] {
+            "program" => RuleKind::Program,
+            "title" => RuleKind::Title,
+            "text" => RuleKind::Text,
+            kind => {
+                return Err(format!(
+                    "unknown line kind {}, expected one of: program, title, text",
+                    kind
+                ))
+            }
+        };
+
+        Ok(TruncationRule {
+            kind,
+            truncation: s[pos + 1..].parse()?,
+        })
+    }
+}
 
 pub enum DisplayKind {
     ProgramTitle,
@@ -9,21 +213,204 @@ pub enum DisplayKind {
     WholeScreenCut,
 }
 
+impl DisplayKind {
+    /// The style of the prefix drawn before the text of lines of this kind.
+    pub fn prefix_style(&self) -> Style {
+        match self {
+            DisplayKind::Text(true) | DisplayKind::MiddleTextCut(true) => Style::bold(Color::Cyan),
+            _ => Style {
+                bold: true,
+                ..Style::default()
+            },
+        }
+    }
+
+    /// The base style of the text of lines of this kind.
+    pub fn text_style(&self) -> Style {
+        match self {
+            DisplayKind::ProgramTitle | DisplayKind::Title(true) => Style::bold(Color::Cyan),
+            _ => Style::default(),
+        }
+    }
+}
+
+/// Colors that fragments can be drawn in. Monochrome terminals show them uncolored.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Color {
+    Cyan,
+    Blue,
+    Green,
+    Red,
+    Gray,
+}
+
+/// Attributes for drawing a fragment of text.
+#[derive(Default, Clone, Copy, PartialEq, Debug)]
+pub struct Style {
+    pub bold: bool,
+    /// Reverse video, e.g. for the fold under the cursor.
+    pub invert: bool,
+    pub fg: Option<Color>,
+}
+
+impl Style {
+    pub fn bold(color: Color) -> Self {
+        Style {
+            bold: true,
+            invert: false,
+            fg: Some(color),
+        }
+    }
+
+    pub fn inverted(self) -> Self {
+        Style {
+            invert: true,
+            ..self
+        }
+    }
+}
+
+/// A piece of a display line, drawn in a single style.
+#[derive(Clone)]
+pub struct Fragment<'a> {
+    pub text: Cow<'a, str>,
+    pub style: Style,
+}
+
+impl<'a> Fragment<'a> {
+    pub fn new(text: impl Into<Cow<'a, str>>, style: Style) -> Self {
+        Fragment {
+            text: text.into(),
+            style,
+        }
+    }
+
+    /// Sub-slice the fragment while keeping it borrowed from the source if it was.
+    fn slice(&self, range: std::ops::Range<usize>) -> Self {
+        let text = match &self.text {
+            Cow::Borrowed(s) => Cow::Borrowed(&s[range]),
+            Cow::Owned(s) => Cow::Owned(s[range].to_owned()),
+        };
+
+        Fragment {
+            text,
+            style: self.style,
+        }
+    }
+}
+
+/// Beyond this many entries, the cache starts over rather than grow further.
+const WIDTH_CACHE_MAX: usize = 1 << 16;
+
+/// How a line of text was fitted to the screen.
+#[derive(Clone)]
+enum Fit {
+    /// As is.
+    Whole,
+    /// Cut after this many bytes, followed by an ellipsis.
+    Prefix(usize),
+    /// Replaced, due to tabs or a truncation other than at the end.
+    Owned(String),
+}
+
+/// Remembers how lines of the programs' content were fitted to the screen, so that redraws of
+/// unchanged content skip tab expansion and truncation. Lines are told apart by the address
+/// and length of their text, which stay the same for as long as the content is kept.
+#[derive(Default, Clone)]
+pub struct WidthCache {
+    cx: usize,
+    fits: HashMap<(usize, usize, usize, Truncation), Fit>,
+}
+
+impl WidthCache {
+    /// Forget everything, for when content is dropped and its addresses may be reused.
+    pub fn clear(&mut self) {
+        self.fits.clear();
+    }
+}
+
 pub struct DisplayLine<'a> {
     pub indent: usize,
     pub kind: DisplayKind,
-    pub prefix: &'static str,
-    pub text: SmallVec<[&'a str; 3]>,
+    pub prefix: Fragment<'static>,
+    pub text: SmallVec<[Fragment<'a>; 3]>,
+}
+
+impl<'a> DisplayLine<'a> {
+    /// A line with a single fragment of text, styled according to its kind.
+    pub fn single(
+        indent: usize,
+        kind: DisplayKind,
+        prefix: impl Into<Cow<'static, str>>,
+        text: impl Into<Cow<'a, str>>,
+    ) -> Self {
+        DisplayLine {
+            indent,
+            prefix: Fragment::new(prefix, kind.prefix_style()),
+            text: SmallVec::from_elem(Fragment::new(text, kind.text_style()), 1),
+            kind,
+        }
+    }
+
+    /// Columns taken by the line, including its indent.
+    pub fn width(&self) -> usize {
+        let text: usize = self.text.iter().map(|f| f.text.chars().count()).sum();
+        self.indent + self.prefix.text.chars().count() + text
+    }
 }
 
 pub struct DisplayDescription<'a> {
     cx: usize,
+    options: &'a DisplayOptions,
+    highlights: Vec<&'a Encapsulation>,
+    /// Numbers of the lines matching the filter, when known from a search index.
+    matches: Option<Vec<usize>>,
     lines: Vec<DisplayLine<'a>>,
 }
 
 impl<'a> DisplayDescription<'a> {
-    pub fn new(cx: usize) -> Self {
-        DisplayDescription { lines: vec![], cx }
+    pub fn new(cx: usize, options: &'a DisplayOptions) -> Self {
+        DisplayDescription {
+            lines: vec![],
+            cx,
+            options,
+            highlights: vec![],
+            matches: None,
+        }
+    }
+
+    /// Have the title of this fold highlighted.
+    pub(crate) fn highlight(&mut self, encapsulation: &'a Encapsulation) {
+        self.highlights.push(encapsulation);
+    }
+
+    /// Use the numbers of the lines matching the filter, in ascending order, rather than
+    /// looking for it in each line.
+    pub(crate) fn set_matches(&mut self, matches: Vec<usize>) {
+        self.matches = Some(matches);
+    }
+
+    /// Columns by which content at the given depth of nesting is indented.
+    fn indent(&self, depth: usize) -> usize {
+        let indentation = &self.options.indentation;
+        let step = if self.cx < indentation.narrow_width {
+            INDENT / 2
+        } else {
+            INDENT
+        };
+
+        (depth * step).min(self.cx * indentation.max_percent / 100)
+    }
+
+    /// Prefix of fold titles at the given depth. Where the indentation was reduced, the depth
+    /// is shown as a number, e.g. `└3─`.
+    fn title_prefix(&self, depth: usize) -> Cow<'static, str> {
+        let glyphs = self.options.glyphs();
+        if self.indent(depth) == depth * INDENT {
+            return glyphs.title.into();
+        }
+
+        format!("{}{}{} ", glyphs.bottom_left, depth, glyphs.horizontal).into()
     }
 
     pub fn lines(&self) -> &Vec<DisplayLine<'a>> {
@@ -31,9 +418,42 @@ impl<'a> DisplayDescription<'a> {
     }
 
     pub fn add_line(&mut self, mut dl: DisplayLine<'a>) {
-        let total_indent = dl.indent + dl.prefix.len();
+        let total_indent = dl.indent + dl.prefix.text.len();
         let elipsis = "...";
-        let cx_remain = self.cx - total_indent - elipsis.len();
+        // On screens too narrow even for the indent, only the ellipsis remains.
+        let cx_remain = self.cx.saturating_sub(total_indent + elipsis.len());
+        let truncation = self.options.truncation.get(&dl.kind);
+
+        // Lines borrowed from content may have been fitted before.
+        let source = match dl.text.as_slice() {
+            [Fragment {
+                text: Cow::Borrowed(text),
+                style,
+            }] => Some((*text, *style)),
+            _ => None,
+        };
+        let key =
+            source.map(|(text, _)| (text.as_ptr() as usize, text.len(), cx_remain, truncation));
+        if let (Some((text, style)), Some(key)) = (source, key) {
+            let mut cache = self.options.width_cache.borrow_mut();
+            if cache.cx != self.cx || cache.fits.len() > WIDTH_CACHE_MAX {
+                cache.cx = self.cx;
+                cache.fits.clear();
+            }
+            if let Some(fit) = cache.fits.get(&key) {
+                dl.text = match fit {
+                    Fit::Whole => SmallVec::from_elem(Fragment::new(text, style), 1),
+                    Fit::Prefix(len) => {
+                        let mut text = SmallVec::from_elem(Fragment::new(&text[..*len], style), 1);
+                        text.push(Fragment::new(elipsis, style));
+                        text
+                    }
+                    Fit::Owned(s) => SmallVec::from_elem(Fragment::new(s.clone(), style), 1),
+                };
+                self.lines.push(dl);
+                return;
+            }
+        }
 
         // Trim, but support wrapping in the future.
 
@@ -43,39 +463,82 @@ impl<'a> DisplayDescription<'a> {
 
         while idx < dl.text.len() {
             // Tab expansion
-            if dl.text[idx].contains('\t') {
-                let mut t = dl.text[idx];
+            if dl.text[idx].text.contains('\t') {
+                let t = dl.text.remove(idx);
                 let mut new_row_x = row_x;
                 let mut new_idx = idx;
-                dl.text.remove(idx);
+                let mut start = 0;
 
-                while let Some(cpos) = t.find('\t') {
-                    dl.text.insert(new_idx, &t[..cpos]);
-                    dl.text
-                        .insert(new_idx + 1, &"        "[..8 - (new_row_x % 8)]);
+                while let Some(cpos) = t.text[start..].find('\t') {
+                    dl.text.insert(new_idx, t.slice(start..start + cpos));
+                    dl.text.insert(
+                        new_idx + 1,
+                        Fragment::new(&"        "[..8 - (new_row_x % 8)], t.style),
+                    );
                     new_row_x += cpos;
-                    t = &t[cpos + 1..];
+                    start += cpos + 1;
                     new_idx += 2;
                 }
-                dl.text.insert(new_idx, &t[..]);
+                dl.text.insert(new_idx, t.slice(start..t.text.len()));
             }
 
             let fragment = &mut dl.text[idx];
-            row_x += fragment.len();
+            row_x += fragment.text.len();
 
-            if row_x > cx_remain {
-                let chunk = &fragment[..fragment.len() - (row_x - cx_remain)];
-                *fragment = chunk;
+            if row_x > cx_remain && last_idx.is_none() {
                 last_idx = Some(idx);
-                break;
+                if truncation == Truncation::End {
+                    let mut end = fragment.text.len() - (row_x - cx_remain);
+                    while !fragment.text.is_char_boundary(end) {
+                        end -= 1;
+                    }
+                    let chunk = fragment.slice(0..end);
+                    *fragment = chunk;
+                    break;
+                }
             }
 
             idx += 1;
         }
 
         if let Some(last_idx) = last_idx {
-            dl.text.truncate(last_idx + 1);
-            dl.text.push(elipsis.into());
+            match truncation {
+                Truncation::End => {
+                    let style = dl.text[last_idx].style;
+                    dl.text.truncate(last_idx + 1);
+                    dl.text.push(Fragment::new(elipsis, style));
+                }
+                _ => {
+                    // These need the whole line, with its tabs expanded above. The result
+                    // takes the style of the start of the line.
+                    let whole: String = dl.text.iter().map(|fragment| &*fragment.text).collect();
+                    let shortened = match truncation {
+                        Truncation::Middle => truncate_middle(&whole, cx_remain, elipsis),
+                        _ => shorten_paths(&whole, cx_remain, elipsis),
+                    };
+                    let style = dl.text[0].style;
+                    dl.text = SmallVec::from_elem(Fragment::new(shortened, style), 1);
+                }
+            }
+        }
+
+        if let (Some((text, _)), Some(key)) = (source, key) {
+            let fit = match dl.text.as_slice() {
+                [Fragment {
+                    text: Cow::Borrowed(fitted),
+                    ..
+                }] if fitted.len() == text.len() => Fit::Whole,
+                [Fragment {
+                    text: Cow::Borrowed(fitted),
+                    ..
+                }, ellipsis]
+                    if fitted.as_ptr() == text.as_ptr() && ellipsis.text == elipsis =>
+                {
+                    Fit::Prefix(fitted.len())
+                }
+                fragments => Fit::Owned(fragments.iter().map(|f| &*f.text).collect()),
+            };
+            self.options.width_cache.borrow_mut().fits.insert(key, fit);
         }
 
         self.lines.push(dl);
@@ -83,43 +546,54 @@ impl<'a> DisplayDescription<'a> {
 
     pub(crate) fn add_content(
         &mut self,
-        content: &'a Vec<Output>,
-        indent: usize,
+        content: &'a [Output],
+        depth: usize,
         allowed_extra: usize,
         last: bool,
     ) {
+        let options = self.options;
+        if let Some(filter) = &options.filter {
+            self.add_filtered_content(content, depth, filter, &mut 0);
+            return;
+        }
+
+        let indent = self.indent(depth);
         let n = content.len();
-        let vertical = "⫼ ";
+        let vertical = options.glyphs().vertical;
         let cut = "+-------------------------------------";
 
+        let mut unchanged = 0;
+
         for (idx, output) in content.iter().enumerate() {
             match output {
-                Output::Encapsulation(encapsulation) => {
-                    if let Some(end_title) = &encapsulation.end_title {
-                        let mut text = SmallVec::new();
-                        text.push(encapsulation.start_title.as_str().into());
-                        if end_title.len() > 0 {
-                            text.push(" ".into());
-                            text.push(end_title.as_str().into());
-                        }
-                        self.add_line(DisplayLine {
-                            indent,
-                            kind: DisplayKind::Title(false),
-                            prefix: "└── ",
-                            text,
-                        });
+                Output::Encapsulation(encapsulation) if options.same_as_last_run(encapsulation) => {
+                    // Runs of unchanged folds take a single line.
+                    unchanged += 1;
+                    let continues = match content.get(idx + 1) {
+                        Some(Output::Encapsulation(next)) => options.same_as_last_run(next),
+                        _ => false,
+                    };
+                    if continues {
+                        continue;
+                    }
+                    if unchanged == 1 {
+                        self.add_title(encapsulation, depth);
                     } else {
-                        let mut text = SmallVec::new();
-                        text.push(encapsulation.start_title.as_str().into());
-                        self.add_line(DisplayLine {
+                        self.add_line(DisplayLine::single(
                             indent,
-                            kind: DisplayKind::Title(true),
-                            prefix: "└── ",
-                            text,
-                        });
+                            DisplayKind::Title(false),
+                            self.title_prefix(depth),
+                            format!("{} folds same as last run", unchanged),
+                        ));
+                    }
+                    unchanged = 0;
+                }
+                Output::Encapsulation(encapsulation) => {
+                    self.add_title(encapsulation, depth);
+                    if !encapsulation.is_ended() {
                         self.add_content(
                             &encapsulation.content,
-                            indent + 4,
+                            depth + 1,
                             allowed_extra,
                             last && idx + 1 == n,
                         );
@@ -140,52 +614,288 @@ impl<'a> DisplayDescription<'a> {
 
                     if nr_lines > minimization_threshold {
                         // First and last_here line
-                        self.add_line(DisplayLine {
+                        self.add_line(DisplayLine::single(
                             indent,
-                            kind: DisplayKind::Text(last_here),
-                            prefix: vertical,
-                            text: SmallVec::from_elem(lines[0].as_str().into(), 1),
-                        });
+                            DisplayKind::Text(last_here),
+                            vertical,
+                            lines[0].as_str(),
+                        ));
                         self.add_line(DisplayLine {
                             indent,
+                            prefix: Fragment::new(
+                                cut,
+                                DisplayKind::MiddleTextCut(last_here).prefix_style(),
+                            ),
                             kind: DisplayKind::MiddleTextCut(last_here),
-                            prefix: cut,
                             text: SmallVec::new(),
                         });
-                        for x in nr_lines - 1 - (minimization_threshold - minimum)..nr_lines {
-                            self.add_line(DisplayLine {
+                        let first = nr_lines - 1 - (minimization_threshold - minimum);
+                        for line in &lines[first..nr_lines] {
+                            self.add_line(DisplayLine::single(
                                 indent,
-                                kind: DisplayKind::Text(last_here),
-                                prefix: vertical,
-                                text: SmallVec::from_elem(lines[x].as_str().into(), 1),
-                            });
+                                DisplayKind::Text(last_here),
+                                vertical,
+                                line.as_str(),
+                            ));
                         }
                     } else {
                         // All lines
                         for line in lines {
-                            self.add_line(DisplayLine {
+                            self.add_line(DisplayLine::single(
+                                indent,
+                                DisplayKind::Text(last_here),
+                                vertical,
+                                line.as_str(),
+                            ));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn add_title(&mut self, encapsulation: &'a Encapsulation, depth: usize) {
+        let kind = DisplayKind::Title(!encapsulation.is_ended());
+        let mut style = kind.text_style();
+        if self
+            .highlights
+            .iter()
+            .any(|highlight| std::ptr::eq(*highlight, encapsulation))
+        {
+            style = style.inverted();
+        }
+
+        let mut text = SmallVec::new();
+        text.push(Fragment::new(encapsulation.start_title.as_str(), style));
+
+        if let Some(end_title) = &encapsulation.end_title {
+            if !end_title.is_empty() {
+                text.push(Fragment::new(" ", style));
+                text.push(Fragment::new(end_title.as_str(), style));
+            }
+            if self.options.line_counts {
+                text.push(Fragment::new(" ", style));
+                text.push(Fragment::new(
+                    line_count_badge(encapsulation.nr_lines, &self.options.format),
+                    style,
+                ));
+            }
+            if self.options.same_as_last_run(encapsulation) {
+                text.push(Fragment::new(" (same as last run)", style));
+            }
+        }
+
+        self.add_line(DisplayLine {
+            indent: self.indent(depth),
+            prefix: Fragment::new(self.title_prefix(depth), kind.prefix_style()),
+            kind,
+            text,
+        });
+    }
+
+    /// Like `add_content`, but only for lines that match the filter. Folds are shown opened
+    /// if anything in them matches, so that matches keep their context. `nr` is the number of
+    /// the first line of the content, counting in arrival order.
+    fn add_filtered_content(
+        &mut self,
+        content: &'a [Output],
+        depth: usize,
+        filter: &str,
+        nr: &mut usize,
+    ) {
+        let indent = self.indent(depth);
+        for output in content {
+            match output {
+                Output::Encapsulation(encapsulation) => {
+                    let ended = encapsulation.end_line.is_some() as usize;
+                    let span = 1 + encapsulation.nr_lines + ended;
+                    let matches = match &self.matches {
+                        Some(matches) => any_in_range(matches, *nr..*nr + span),
+                        None => encapsulation_matches(encapsulation, filter),
+                    };
+
+                    if matches {
+                        self.add_title(encapsulation, depth);
+                        *nr += 1;
+                        self.add_filtered_content(&encapsulation.content, depth + 1, filter, nr);
+                        *nr += ended;
+                    } else {
+                        *nr += span;
+                    }
+                }
+                Output::Lines(lines) => {
+                    for (idx, line) in lines.iter().enumerate() {
+                        let matches = match &self.matches {
+                            Some(matches) => matches.binary_search(&(*nr + idx)).is_ok(),
+                            None => line.contains(filter),
+                        };
+                        if matches {
+                            self.add_line(DisplayLine::single(
                                 indent,
-                                kind: DisplayKind::Text(last_here),
-                                prefix: vertical,
-                                text: SmallVec::from_elem(line.as_str().into(), 1),
-                            });
+                                DisplayKind::Text(false),
+                                self.options.glyphs().vertical,
+                                line.as_str(),
+                            ));
                         }
                     }
+                    *nr += lines.len();
                 }
             }
         }
     }
 
+    /// Keep the title and the last lines, so that `count` lines remain, including the one
+    /// marking the cut.
     pub fn reduce_to_count(&mut self, count: usize) {
-        self.lines.drain(1..self.lines.len() - count + 1);
+        let len = self.lines.len();
+        if count >= len {
+            return;
+        }
+        if count < 2 {
+            // No room for marking the cut.
+            self.lines.truncate(count);
+            return;
+        }
+
+        self.lines.drain(1..len - count + 2);
         self.lines.insert(
             1,
             DisplayLine {
                 indent: 0,
                 kind: DisplayKind::WholeScreenCut,
-                prefix: "",
+                prefix: Fragment::new("", Style::default()),
                 text: SmallVec::new(),
             },
         );
     }
 }
+
+fn any_in_range(sorted: &[usize], range: std::ops::Range<usize>) -> bool {
+    let first = sorted.partition_point(|nr| *nr < range.start);
+    first < sorted.len() && sorted[first] < range.end
+}
+
+fn encapsulation_matches(encapsulation: &Encapsulation, filter: &str) -> bool {
+    encapsulation.start_line.contains(filter)
+        || encapsulation
+            .end_line
+            .as_ref()
+            .is_some_and(|line| line.contains(filter))
+        || content_matches(&encapsulation.content, filter)
+}
+
+fn content_matches(content: &[Output], filter: &str) -> bool {
+    content.iter().any(|output| match output {
+        Output::Lines(lines) => lines.iter().any(|line| line.contains(filter)),
+        Output::Encapsulation(encapsulation) => encapsulation_matches(encapsulation, filter),
+    })
+}
+
+/// Describe an amount of hidden lines, e.g. `(1,234 lines)`.
+fn line_count_badge(nr_lines: usize, format: &Format) -> String {
+    if nr_lines == 1 {
+        "(1 line)".to_owned()
+    } else {
+        format!("({} lines)", format.count(nr_lines as u64))
+    }
+}
+
+/// Keep the start and the end of `text`, so that it fits in `room` characters plus the
+/// ellipsis placed between them.
+fn truncate_middle(text: &str, room: usize, elipsis: &str) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    if chars.len() <= room {
+        return text.to_owned();
+    }
+
+    let head = room / 2;
+    let tail = room - head;
+
+    let mut shortened: String = chars[..head].iter().collect();
+    shortened.push_str(elipsis);
+    shortened.extend(&chars[chars.len() - tail..]);
+    shortened
+}
+
+/// Shorten the paths in `text`, longest first, by replacing their leading components with an
+/// ellipsis, until it fits in `room` characters. Falls back to chopping the end.
+fn shorten_paths(text: &str, room: usize, elipsis: &str) -> String {
+    let mut words: Vec<String> = text.split(' ').map(str::to_owned).collect();
+    let mut len = text.chars().count();
+
+    while len > room {
+        let candidate = words
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, word)| drop_path_component(word, elipsis).map(|short| (idx, short)))
+            .max_by_key(|(idx, _)| words[*idx].len());
+
+        let (idx, short) = match candidate {
+            Some(candidate) => candidate,
+            None => break,
+        };
+
+        len = len - words[idx].chars().count() + short.chars().count();
+        words[idx] = short;
+    }
+
+    let mut shortened = words.join(" ");
+    if len > room {
+        shortened = shortened.chars().take(room).collect();
+        shortened.push_str(elipsis);
+    }
+    shortened
+}
+
+/// The word with the leading component of its path replaced by an ellipsis, if it has one and
+/// that makes it shorter.
+fn drop_path_component(word: &str, elipsis: &str) -> Option<String> {
+    let marker = format!("{}/", elipsis);
+    let rest = match word.strip_prefix(marker.as_str()) {
+        Some(rest) => rest,
+        None => word.trim_start_matches('/'),
+    };
+
+    let pos = rest.find('/')?;
+    let short = format!("{}{}", marker, &rest[pos + 1..]);
+    if short.len() < word.len() {
+        Some(short)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn middle_truncation() {
+        assert_eq!(truncate_middle("abc", 3, "..."), "abc");
+        assert_eq!(truncate_middle("abcdefghij", 4, "..."), "ab...ij");
+        assert_eq!(truncate_middle("abcd", 3, "…"), "a…cd");
+        assert_eq!(truncate_middle("日本語テキスト", 4, "…"), "日本…スト");
+        assert_eq!(truncate_middle("abc", 0, "…"), "…");
+    }
+
+    #[test]
+    fn path_shortening() {
+        let text = "cc /usr/src/linux/kernel/sched.c -o x";
+        assert_eq!(shorten_paths(text, 37, "..."), text);
+        assert_eq!(
+            shorten_paths(text, 36, "..."),
+            "cc .../src/linux/kernel/sched.c -o x"
+        );
+        assert_eq!(shorten_paths(text, 30, "..."), "cc .../kernel/sched.c -o x");
+        assert_eq!(shorten_paths(text, 20, "…"), "cc …/sched.c -o x");
+
+        // The longest path goes first.
+        let text = "/a/b/c /src/project/main.rs";
+        assert_eq!(shorten_paths(text, 26, "…"), "/a/b/c …/project/main.rs");
+
+        // What cannot be shortened any further is cut at the end.
+        assert_eq!(shorten_paths("no paths here", 8, "..."), "no paths...");
+        assert_eq!(shorten_paths("cc /src/main.rs", 8, "…"), "cc …/mai…");
+        assert_eq!(shorten_paths("a/b", 2, "..."), "a/...");
+    }
+}
@@ -0,0 +1,213 @@
+use super::util::parse_duration;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Selects folds by their properties, e.g. `outcome=failed && duration>30s`.
+///
+/// Conditions are joined by `&&`, and groups of them by `||`. A condition compares a field
+/// with `=`, `!=`, `>`, `>=`, `<`, `<=`, or `~` (contains). The fields are `program`,
+/// `label`, `title`, `outcome` (one of ok, failed, killed, running), `duration`, and the
+/// names of captures kept as fold metadata. A field that a fold lacks matches nothing.
+#[derive(Debug, Clone)]
+pub struct Filter {
+    any: Vec<Vec<Condition>>,
+}
+
+#[derive(Debug, Clone)]
+struct Condition {
+    field: String,
+    op: Op,
+    value: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    Eq,
+    Ne,
+    Gt,
+    Ge,
+    Lt,
+    Le,
+    Contains,
+}
+
+/// Longer operators first, so that `>=` is not taken for `>`.
+const OPS: [(&str, Op); 7] = [
+    ("!=", Op::Ne),
+    (">=", Op::Ge),
+    ("<=", Op::Le),
+    ("=", Op::Eq),
+    (">", Op::Gt),
+    ("<", Op::Lt),
+    ("~", Op::Contains),
+];
+
+impl FromStr for Filter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let any = s
+            .split("||")
+            .map(|all| all.split("&&").map(str::parse).collect())
+            .collect::<Result<_, _>>()?;
+
+        Ok(Filter { any })
+    }
+}
+
+impl FromStr for Condition {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let found = s.find(|c| "!=<>~".contains(c)).and_then(|pos| {
+            OPS.iter()
+                .find(|(token, _)| s[pos..].starts_with(token))
+                .map(|(token, op)| (pos, token.len(), *op))
+        });
+        let (pos, len, op) = found.ok_or_else(|| format!("no comparison in condition {}", s))?;
+
+        let field = s[..pos].trim();
+        let value = s[pos + len..].trim();
+        if field.is_empty() {
+            return Err(format!("no field in condition {}", s));
+        }
+        if field == "duration" {
+            parse_duration(value)?;
+        }
+
+        Ok(Condition {
+            field: field.to_owned(),
+            op,
+            value: value.to_owned(),
+        })
+    }
+}
+
+/// What a filter looks at in a fold.
+pub struct Subject<'a> {
+    pub program: &'a str,
+    pub label: Option<&'a str>,
+    pub title: &'a str,
+    pub outcome: &'a str,
+    pub duration: Duration,
+    pub metadata: &'a [(String, String)],
+}
+
+impl Filter {
+    pub fn matches(&self, subject: &Subject<'_>) -> bool {
+        self.any
+            .iter()
+            .any(|all| all.iter().all(|condition| condition.matches(subject)))
+    }
+}
+
+impl Condition {
+    fn matches(&self, subject: &Subject<'_>) -> bool {
+        if self.field == "duration" {
+            let value = parse_duration(&self.value).unwrap_or_default();
+            return self.compare(subject.duration.cmp(&value), false);
+        }
+
+        let field = match self.field.as_str() {
+            "program" => Some(subject.program),
+            "label" => subject.label,
+            "title" => Some(subject.title),
+            "outcome" => Some(subject.outcome),
+            name => subject
+                .metadata
+                .iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.as_str()),
+        };
+        let field = match field {
+            Some(field) => field,
+            None => return false,
+        };
+
+        // Numbers compare as such, everything else as text.
+        let ordering = match (field.parse::<f64>(), self.value.parse::<f64>()) {
+            (Ok(a), Ok(b)) => a.partial_cmp(&b),
+            _ => Some(field.cmp(self.value.as_str())),
+        };
+        match ordering {
+            Some(ordering) => self.compare(ordering, field.contains(self.value.as_str())),
+            None => false,
+        }
+    }
+
+    fn compare(&self, ordering: std::cmp::Ordering, contains: bool) -> bool {
+        use std::cmp::Ordering::*;
+
+        match self.op {
+            Op::Eq => ordering == Equal,
+            Op::Ne => ordering != Equal,
+            Op::Gt => ordering == Greater,
+            Op::Ge => ordering != Less,
+            Op::Lt => ordering == Less,
+            Op::Le => ordering != Greater,
+            Op::Contains => contains,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn subject(metadata: &[(String, String)]) -> Subject<'_> {
+        Subject {
+            program: "make -j8",
+            label: None,
+            title: "Building foo",
+            outcome: "failed",
+            duration: Duration::from_secs(45),
+            metadata,
+        }
+    }
+
+    fn matches(filter: &str) -> bool {
+        let metadata = [("shard".to_owned(), "12".to_owned())];
+        filter
+            .parse::<Filter>()
+            .unwrap()
+            .matches(&subject(&metadata))
+    }
+
+    #[test]
+    fn conditions() {
+        assert!(matches("outcome=failed"));
+        assert!(!matches("outcome!=failed"));
+        assert!(matches("title~foo"));
+        assert!(matches("program = make -j8"));
+        assert!(matches("duration>30s"));
+        assert!(matches("duration<=45s"));
+        assert!(!matches("duration<1m && duration>45s"));
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        assert!(matches("outcome=ok || outcome=failed && duration>30s"));
+        assert!(!matches("outcome=ok || outcome=failed && duration>1m"));
+    }
+
+    #[test]
+    fn metadata_compares_as_numbers() {
+        assert!(matches("shard>9"));
+        assert!(matches("shard>=12"));
+        assert!(!matches("shard<2"));
+    }
+
+    #[test]
+    fn missing_fields_match_nothing() {
+        assert!(!matches("label=x"));
+        assert!(!matches("label!=x"));
+        assert!(!matches("zone~eu"));
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        for s in ["", "outcome", "=failed", "duration>soon", "title!foo"] {
+            assert!(s.parse::<Filter>().is_err(), "{}", s);
+        }
+    }
+}
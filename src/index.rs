@@ -0,0 +1,170 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+/// Finds the lines of a program containing a given text without scanning all of them, by
+/// keeping for each trigram the lines in which it appears. Lines are referred to by their
+/// number in arrival order, and are not kept here, as the program has them already.
+#[derive(Default)]
+pub struct SearchIndex {
+    /// Amount of lines indexed.
+    lines: usize,
+    /// Line numbers for each trigram, in ascending order.
+    trigrams: HashMap<[u8; 3], Vec<u32>>,
+    /// The last search, which the next one for the same text only extends to the lines that
+    /// arrived since, as lines do not change once they arrive.
+    last: RefCell<Option<Search>>,
+}
+
+struct Search {
+    needle: String,
+    lines: usize,
+    matches: Vec<usize>,
+}
+
+impl SearchIndex {
+    pub fn push(&mut self, line: &str) {
+        let nr = self.lines as u32;
+        self.lines += 1;
+
+        let mut seen = trigrams(line);
+        seen.sort_unstable();
+        seen.dedup();
+        for trigram in seen {
+            self.trigrams.entry(trigram).or_default().push(nr);
+        }
+    }
+
+    /// Numbers of the lines containing `needle`, in ascending order. The lines are checked
+    /// through `for_each_line`, which is to visit all of them in arrival order.
+    pub fn search<'a>(
+        &self,
+        needle: &str,
+        for_each_line: impl FnOnce(&mut dyn FnMut(&'a str)),
+    ) -> Vec<usize> {
+        let mut last = self.last.borrow_mut();
+        let (start, mut matches) = match last.take() {
+            Some(search) if search.needle == needle => {
+                if search.lines == self.lines {
+                    let matches = search.matches.clone();
+                    *last = Some(search);
+                    return matches;
+                }
+                (search.lines, search.matches)
+            }
+            _ => (0, vec![]),
+        };
+
+        // Without trigrams to narrow it down, every new line is a candidate.
+        let candidates = self.candidates(needle, start as u32);
+        let any = match &candidates {
+            Some(candidates) => !candidates.is_empty(),
+            None => start < self.lines,
+        };
+        if any {
+            let mut candidates = candidates.as_deref().map(|c| c.iter().peekable());
+            let mut nr = 0;
+            for_each_line(&mut |line| {
+                let candidate = match &mut candidates {
+                    Some(candidates) => candidates.next_if_eq(&&(nr as u32)).is_some(),
+                    None => nr >= start,
+                };
+                // Having all the trigrams does not mean having them in the right order.
+                if candidate && line.contains(needle) {
+                    matches.push(nr);
+                }
+                nr += 1;
+            });
+        }
+
+        *last = Some(Search {
+            needle: needle.to_owned(),
+            lines: self.lines,
+            matches: matches.clone(),
+        });
+        matches
+    }
+
+    /// Numbers of the lines from `start` on having all the trigrams of `needle`, or `None` if
+    /// it is too short to have any.
+    fn candidates(&self, needle: &str, start: u32) -> Option<Vec<u32>> {
+        let mut needed = trigrams(needle);
+        if needed.is_empty() {
+            return None;
+        }
+
+        needed.sort_unstable();
+        needed.dedup();
+
+        let mut postings = vec![];
+        for trigram in &needed {
+            match self.trigrams.get(trigram) {
+                Some(posting) => {
+                    let from = posting.partition_point(|nr| *nr < start);
+                    postings.push(&posting[from..]);
+                }
+                None => return Some(vec![]),
+            }
+        }
+
+        // Intersect starting from the rarest trigram, so that the candidates shrink fast.
+        postings.sort_by_key(|posting| posting.len());
+        let mut candidates = postings[0].to_vec();
+        for posting in &postings[1..] {
+            candidates.retain(|nr| posting.binary_search(nr).is_ok());
+        }
+
+        Some(candidates)
+    }
+}
+
+fn trigrams(s: &str) -> Vec<[u8; 3]> {
+    s.as_bytes()
+        .windows(3)
+        .map(|window| [window[0], window[1], window[2]])
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn index(lines: &[&'static str]) -> SearchIndex {
+        let mut index = SearchIndex::default();
+        for line in lines {
+            index.push(line);
+        }
+        index
+    }
+
+    fn search(index: &SearchIndex, lines: &[&'static str], needle: &str) -> Vec<usize> {
+        index.search(needle, |f| lines.iter().for_each(|line| f(line)))
+    }
+
+    #[test]
+    fn finds_lines() {
+        let lines = [
+            "compiling foo",
+            "error: bar",
+            "compiling baz",
+            "warning: rorre",
+        ];
+        let index = index(&lines);
+        assert_eq!(search(&index, &lines, "compiling"), vec![0, 2]);
+        assert_eq!(search(&index, &lines, "error"), vec![1]);
+        assert_eq!(search(&index, &lines, "ba"), vec![1, 2]);
+        assert_eq!(search(&index, &lines, "nothing"), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn extends_the_last_search() {
+        let mut lines = vec!["error: one", "fine"];
+        let mut index = index(&lines);
+        assert_eq!(search(&index, &lines, "error"), vec![0]);
+
+        lines.push("error: two");
+        index.push("error: two");
+        assert_eq!(search(&index, &lines, "error"), vec![0, 2]);
+        assert_eq!(search(&index, &lines, "err"), vec![0, 2]);
+        assert_eq!(search(&index, &lines, "fine"), vec![1]);
+    }
+}
@@ -0,0 +1,92 @@
+use super::Sender;
+use std::fs::File;
+use std::io::Read;
+use std::os::unix::io::AsRawFd;
+use termion::event::{Event, Key};
+
+/// Keyboard input from the controlling terminal. Our own stdin may be the output we are
+/// folding, so keys are always read from `/dev/tty`.
+///
+/// While alive, the terminal is kept in non-canonical mode without echo. Signal generation is
+/// left intact so that Ctrl+C still reaches us, and Ctrl+\ unless it is asked to be taken as
+/// a key.
+pub struct Tty {
+    file: File,
+    saved: libc::termios,
+}
+
+impl Tty {
+    pub fn open(quit_key: bool) -> std::io::Result<Self> {
+        let file = termion::get_tty()?;
+        let fd = file.as_raw_fd();
+
+        let mut saved: libc::termios = unsafe { std::mem::zeroed() };
+        if unsafe { libc::tcgetattr(fd, &mut saved) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        let mut attrs = saved;
+        attrs.c_lflag &= !(libc::ICANON | libc::ECHO);
+        if quit_key {
+            attrs.c_cc[libc::VQUIT] = 0;
+        }
+        attrs.c_cc[libc::VMIN] = 1;
+        attrs.c_cc[libc::VTIME] = 0;
+        if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &attrs) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        Ok(Self { file, saved })
+    }
+
+    /// Forward key presses to the given sender from a dedicated thread, as reading from the
+    /// terminal is blocking.
+    pub fn spawn_reader(&self, sender: Sender<Key>) -> std::io::Result<()> {
+        let mut file = self.file.try_clone()?;
+
+        std::thread::spawn(move || {
+            let mut buf = [0u8; 64];
+            loop {
+                let n = match file.read(&mut buf) {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => n,
+                };
+                for key in parse_keys(&buf[..n]) {
+                    if sender.unbounded_send(key).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+
+        Ok(())
+    }
+}
+
+impl Drop for Tty {
+    fn drop(&mut self) {
+        unsafe {
+            libc::tcsetattr(self.file.as_raw_fd(), libc::TCSANOW, &self.saved);
+        }
+    }
+}
+
+/// Parse the bytes of a single read. An escape byte that ends the read is taken as a lone Esc
+/// key press rather than the start of an incomplete sequence.
+fn parse_keys(bytes: &[u8]) -> Vec<Key> {
+    let mut keys = vec![];
+    let mut iter = bytes.iter().map(|b| Ok::<u8, std::io::Error>(*b));
+
+    while let Some(Ok(byte)) = iter.next() {
+        if byte == 0x1b && iter.len() == 0 {
+            keys.push(Key::Esc);
+            continue;
+        }
+
+        if let Ok(Event::Key(key)) = termion::event::parse_event(byte, &mut iter) {
+            keys.push(key);
+        }
+    }
+
+    keys
+}
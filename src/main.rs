@@ -1,24 +1,42 @@
-#![recursion_limit = "256"]
+#![recursion_limit = "512"]
 use anyhow::Result;
+use futures::stream::{BoxStream, SelectAll};
 use futures::FutureExt;
 use futures::SinkExt;
 use lazy_static::lazy_static;
 use regex::{Regex, RegexSet};
 use slab::Slab;
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::io::{stdout, BufRead, BufWriter, Stdout, Write};
-use structopt::StructOpt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use termion::screen::AlternateScreen;
 use thiserror::Error;
 
+mod announce;
 mod cmdline;
+mod diff;
 mod display;
+mod filter;
+mod index;
+mod input;
+mod manifest;
+mod play;
+mod profile;
 mod program;
+mod redact;
+mod remote;
+mod rewrite;
+mod session;
+mod spool;
+mod update;
 mod util;
 
-use display::DisplayKind;
+use display::{DisplayKind, DisplayLine, DisplayOptions};
 use futures::channel::mpsc;
 use program::Program;
+use termion::event;
 use util::most_equal_divide;
 
 type Sender<T> = mpsc::UnboundedSender<T>;
@@ -27,6 +45,12 @@ type Key = usize;
 type Text = String;
 type PairId = usize;
 
+/// How long to run before pointing out regexes that did not match anything in the status line.
+const UNMATCHED_GRACE: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// How long output has to pause on an incomplete line before it is taken as a prompt.
+const PROMPT_PAUSE: std::time::Duration = std::time::Duration::from_millis(50);
+
 #[derive(Error, Debug)]
 pub(crate) enum Error {
     #[error("Start and end matchers count dont match: {0} != {1}")]
@@ -43,16 +67,66 @@ pub(crate) enum Error {
 
     #[error("No programs specified")]
     NoPrograms,
+
+    #[error("Session file {0} has unsupported version {1}")]
+    SessionVersion(String, u32),
+
+    #[error("A session name is required to run in the background")]
+    SessionNameRequired,
+
+    #[error("Session {0} is already running")]
+    SessionExists(String),
+
+    #[error("No session named {0} is running")]
+    SessionNotFound(String),
+
+    #[error("Nothing is mirrored at {0}")]
+    MirrorNotFound(String),
+
+    #[error("Unexpected data from session {0}")]
+    SessionProtocol(String),
+
+    #[error("Invalid directive in programs file, expected 'key: value': {0}")]
+    ManifestDirective(String),
+
+    #[error("Unknown program setting: {0}")]
+    ManifestUnknownKey(String),
+
+    #[error("Invalid value for program setting {0}: {1}")]
+    ManifestSetting(String, String),
+
+    #[error("Total timeout of {0} exceeded")]
+    TotalTimeout(String),
+
+    #[error("Failed to query the latest release: {0}")]
+    UpdateQuery(String),
+
+    #[error("Release {0} has no binary for this platform ({1})")]
+    UpdateNoAsset(String, String),
+
+    #[error("Failed to download the latest release: {0}")]
+    UpdateDownload(String),
+
+    #[error("Release {0} publishes no checksum for {1}")]
+    UpdateNoChecksum(String, String),
+
+    #[error("The download of {0} does not match its published checksum")]
+    UpdateChecksum(String),
 }
 
 struct Encapsulation {
-    #[allow(unused)]
     pair_id: PairId,
     start_title: Text,
     end_title: Option<Text>,
     start_line: Text,
     end_line: Option<Text>,
     content: Vec<Output>,
+    /// Amount of lines nested under this encapsulation, not counting its own start and end.
+    nr_lines: usize,
+    /// Hash of all the lines of the fold, including its start and end, once it ended.
+    hash: Option<u64>,
+    /// Named captures of the start regex other than `M`, in the order of the regex.
+    metadata: Vec<(String, Text)>,
 }
 
 impl Encapsulation {
@@ -66,23 +140,222 @@ enum Output {
     Encapsulation(Encapsulation),
 }
 
+/// What the broker receives for a program.
+enum Input {
+    /// Read by one of the reader tasks of the program.
+    Line(Result<Text, std::io::Error>),
+    /// Relayed from a remote session, along with its original arrival time.
+    Remote(std::time::Duration, Text),
+    /// The program of a remote session has terminated.
+    RemoteOutcome(program::Outcome),
+    /// A remote session added a program, with the given description and label.
+    RemoteProgram(Text, Option<String>),
+    /// For programs that have prompts to answer, the last line read so far by `reader` while
+    /// it waits for more, and then again once it is `complete`.
+    Prompt {
+        reader: usize,
+        text: Text,
+        complete: bool,
+    },
+}
+
+impl Input {
+    /// The complete line read by the program, if this is one.
+    fn line(&self) -> Option<&Text> {
+        match self {
+            Input::Line(Ok(text)) => Some(text),
+            Input::Prompt {
+                text,
+                complete: true,
+                ..
+            } => Some(text),
+            _ => None,
+        }
+    }
+
+    fn line_mut(&mut self) -> Option<&mut Text> {
+        match self {
+            Input::Line(Ok(text)) => Some(text),
+            Input::Prompt {
+                text,
+                complete: true,
+                ..
+            } => Some(text),
+            _ => None,
+        }
+    }
+}
+
 struct MatchPair {
     start: Regex,
     end: Regex,
+    /// How many lines each of the regexes matched.
+    hits: std::cell::Cell<(usize, usize)>,
+}
+
+impl MatchPair {
+    fn new(start: Regex, end: Regex) -> Self {
+        MatchPair {
+            start,
+            end,
+            hits: Default::default(),
+        }
+    }
 }
 
 struct Matchers<'a> {
     match_pairs: &'a Vec<MatchPair>,
     regex_set: &'a RegexSet,
+    /// Lines of all programs so far, for numbering them in order of arrival.
+    sequence: &'a std::cell::Cell<u64>,
+}
+
+/// Counts what waits for the broker, so a program falling behind can be shown.
+struct Feed {
+    sender: Sender<(Key, Input)>,
+    backlog: Arc<AtomicUsize>,
+}
+
+impl Feed {
+    async fn send(&mut self, message: (Key, Input)) -> Result<()> {
+        self.backlog.fetch_add(1, Ordering::Relaxed);
+        if let Err(err) = self.sender.send(message).await {
+            self.backlog.fetch_sub(1, Ordering::Relaxed);
+            return Err(err.into());
+        }
+        Ok(())
+    }
+}
+
+/// How the reader tasks of programs take what they read into lines.
+#[derive(Clone, Copy)]
+struct Reading {
+    invalid_utf8: util::InvalidUtf8,
+    /// Bytes to keep of each line, 0 for all of them.
+    max_line_length: usize,
+    format: util::Format,
+}
+
+/// A line being read, of which at most `Reading::max_line_length` bytes are kept.
+#[derive(Default)]
+struct PartialLine {
+    buf: Vec<u8>,
+    /// The length that the line has in full.
+    len: usize,
+    /// Whether what was read last ends with a carriage return.
+    cr: bool,
+}
+
+impl PartialLine {
+    fn extend(&mut self, bytes: &[u8], max: usize) {
+        self.len += bytes.len();
+        if let Some(last) = bytes.last() {
+            self.cr = *last == b'\r';
+        }
+        let room = match max {
+            0 => bytes.len(),
+            max => max.saturating_sub(self.buf.len()).min(bytes.len()),
+        };
+        self.buf.extend_from_slice(&bytes[..room]);
+    }
+
+    /// The line without the carriage return ending it, if any, marked as truncated where it
+    /// was cut.
+    fn text(&self, reading: &Reading) -> String {
+        let len = self.len - self.cr as usize;
+        let buf = &self.buf[..self.buf.len().min(len)];
+        let text = reading.invalid_utf8.decode(buf);
+        if len > buf.len() {
+            truncate(&text, reading.max_line_length, len, &reading.format)
+        } else {
+            text
+        }
+    }
+
+    fn clear(&mut self) {
+        *self = Self::default();
+    }
+}
+
+/// A program waiting for its turn to start.
+struct Queued {
+    key: Key,
+    at: std::time::Instant,
+    argv: Vec<String>,
+    settings: manifest::Settings,
+    foreground: bool,
 }
 
 struct Main {
-    receiver: Receiver<(Key, Result<Text, std::io::Error>)>,
-    sender: Option<Sender<(Key, Result<Text, std::io::Error>)>>,
+    /// One channel per program, so that a chatty program does not hold up the others.
+    receivers: SelectAll<BoxStream<'static, (Key, Input)>>,
+    /// Programs still arriving from a programs file read from stdin. While this is open, the
+    /// broker sender is kept for spawning them.
+    entries: Option<Receiver<Result<manifest::Entry>>>,
+    /// Programs not started yet, due to `--stagger` or their own delay. The broker sender is
+    /// kept for them as well.
+    queue: Vec<Queued>,
+    /// Under `--stagger`, the earliest time at which the next program may start.
+    next_start: Option<std::time::Instant>,
+    /// Held while more programs may come, so the broker does not end early.
+    sender: Option<Sender<(Key, Input)>>,
     opt: cmdline::Opt,
     programs: Slab<Program>,
     match_pairs: Vec<MatchPair>,
     regex_set: RegexSet,
+    display_options: DisplayOptions,
+    profile: profile::Profile,
+    /// The filter query is being typed in.
+    filter_editing: bool,
+    /// Program picked with Tab, for the commands that act on a single program.
+    selected: Option<Key>,
+    /// Programs pinned for side by side comparison. Two pinned programs take over the screen.
+    pinned: Vec<Key>,
+    /// Fold under the cursor in the selected program, moved with `[` and `]`.
+    fold_cursor: Option<Vec<usize>>,
+    /// Folds marked with `m` for comparison.
+    marked: Vec<(Key, Vec<usize>)>,
+    /// Popup showing the differences between the two marked folds.
+    diff_view: Option<DiffView>,
+    /// Scroll position of the help overlay, while it is shown.
+    help: Option<usize>,
+    /// Ctrl+C was pressed once and the children were asked to terminate.
+    terminating: bool,
+    /// The children were killed for exceeding `--total-timeout`.
+    timed_out: bool,
+    /// Our output is not a terminal, so nothing is drawn.
+    piped: bool,
+    last_heartbeat: std::time::Instant,
+    /// With `--redact`, replaces secrets as soon as lines arrive.
+    redactor: Option<redact::Redactor>,
+    /// Amount of secrets replaced so far.
+    redactions: usize,
+    /// When the line rates of the programs were last updated.
+    last_rate_sample: std::time::Instant,
+    /// With `--announce`, the events to be told about.
+    announcer: Option<announce::Announcer>,
+    /// With `play`, the saved session being played back.
+    player: Option<play::Player>,
+    /// With `--spool`, where snapshots of the live session go.
+    spool: Option<spool::Spool>,
+    /// With `--mirror`, where viewers connect.
+    mirror: Option<async_std::os::unix::net::UnixListener>,
+    /// Problems to tell about at the end, that did not stop the run.
+    warnings: Vec<String>,
+    /// With `--long-lines-file`, where lines go in full before being truncated.
+    long_lines: Option<File>,
+    /// Line arrival times are kept relative to this.
+    start: std::time::Instant,
+    /// The wall clock time of `start`, for showing arrival times.
+    started_at: std::time::SystemTime,
+    /// Lines of all programs so far, which orders lines arriving at the same time.
+    sequence: std::cell::Cell<u64>,
+}
+
+struct DiffView {
+    title: String,
+    lines: Vec<diff::DiffLine>,
+    scroll: usize,
 }
 
 enum DrawMode {
@@ -91,15 +364,66 @@ enum DrawMode {
 }
 
 impl Main {
-    fn new() -> Self {
+    fn new(opt: cmdline::Opt) -> Self {
         let (broker_sender, broker_receiver) = mpsc::unbounded();
+        let mut receivers = SelectAll::new();
+        receivers.push(futures::StreamExt::boxed(broker_receiver));
 
         let a: &[&String] = &[];
+        let profile = opt.profile.unwrap_or_else(profile::detect);
+        let mut display_options = DisplayOptions {
+            line_counts: opt.line_counts,
+            timeline: opt.timeline,
+            ascii: !profile.unicode,
+            format: util::Format::from_locale(opt.time_format),
+            indentation: display::Indentation {
+                narrow_width: opt.narrow_width,
+                max_percent: opt.max_indent,
+            },
+            ..DisplayOptions::default()
+        };
+        for rule in &opt.truncate {
+            display_options.truncation.set(rule);
+        }
+        let announcer = opt.announce.map(announce::Announcer::new);
+        let redactor = if opt.redact || !opt.redact_patterns.is_empty() {
+            Some(redact::Redactor::new(&opt.redact_patterns))
+        } else {
+            None
+        };
 
         Self {
-            opt: cmdline::Opt::from_args(),
+            opt,
+            display_options,
+            profile,
+            filter_editing: false,
+            selected: None,
+            pinned: vec![],
+            fold_cursor: None,
+            marked: vec![],
+            diff_view: None,
+            help: None,
+            terminating: false,
+            timed_out: false,
+            piped: !termion::is_tty(&stdout()),
+            last_heartbeat: std::time::Instant::now(),
+            redactor,
+            redactions: 0,
+            player: None,
+            spool: None,
+            mirror: None,
+            warnings: vec![],
+            long_lines: None,
+            announcer,
+            last_rate_sample: std::time::Instant::now(),
+            start: std::time::Instant::now(),
+            started_at: std::time::SystemTime::now(),
+            sequence: Default::default(),
             programs: Slab::new(),
-            receiver: broker_receiver,
+            receivers,
+            entries: None,
+            queue: vec![],
+            next_start: None,
             sender: Some(broker_sender),
             match_pairs: vec![],
             regex_set: RegexSet::new(a).unwrap(),
@@ -129,21 +453,17 @@ impl Main {
         Ok(r)
     }
 
-    fn run(&mut self) -> Result<()> {
+    fn load_match_pairs(&mut self) -> Result<()> {
         let s = self.opt.match_start.len();
         let e = self.opt.match_end.len();
         if s != e {
             return Err(Error::MatchPairInvalid(e, s).into());
         }
 
-        let mut regex_set = vec![];
         for (start, end) in itertools::zip(&self.opt.match_start, &self.opt.match_end) {
             let start = Self::regex(start)?;
             let end = Self::regex(end)?;
-            let pair = MatchPair { start, end };
-            regex_set.push(String::from(pair.start.as_str()));
-            regex_set.push(String::from(pair.end.as_str()));
-            self.match_pairs.push(pair);
+            self.match_pairs.push(MatchPair::new(start, end));
         }
 
         if let Some(match_pairs_file) = &self.opt.match_pairs_file {
@@ -157,10 +477,7 @@ impl Main {
 
                 let start = Self::regex(&start.take().unwrap())?;
                 let end = Self::regex(&line?)?;
-                let pair = MatchPair { start, end };
-                regex_set.push(String::from(pair.start.as_str()));
-                regex_set.push(String::from(pair.end.as_str()));
-                self.match_pairs.push(pair);
+                self.match_pairs.push(MatchPair::new(start, end));
             }
 
             if let Some(start) = start {
@@ -168,21 +485,146 @@ impl Main {
             }
         }
 
-        self.regex_set = RegexSet::new(&regex_set)?;
+        self.build_regex_set()
+    }
 
-        self.load_programs()?;
+    fn build_regex_set(&mut self) -> Result<()> {
+        let patterns = self
+            .match_pairs
+            .iter()
+            .flat_map(|pair| vec![pair.start.as_str(), pair.end.as_str()]);
+        self.regex_set = RegexSet::new(patterns)?;
 
-        if self.programs.is_empty() {
-            if self.opt.programs_file.is_none() {
-                self.insert_stdin()?;
-            } else {
-                return Err(Error::NoPrograms.into());
+        Ok(())
+    }
+
+    /// Rebuild the programs of a saved session by feeding their lines again.
+    fn restore_session(&mut self, session: session::Session) -> Result<()> {
+        for pair in &session.match_pairs {
+            let start = Regex::new(&pair.start)?;
+            let end = Regex::new(&pair.end)?;
+            self.match_pairs.push(MatchPair::new(start, end));
+        }
+
+        self.build_regex_set()?;
+
+        let matchers = Matchers {
+            match_pairs: &self.match_pairs,
+            regex_set: &self.regex_set,
+            sequence: &self.sequence,
+        };
+        let mut sequence = self.sequence.get();
+
+        for saved in session.programs {
+            let mut program = Program::new(saved.desc, vec![])
+                .with_index(self.opt.index)
+                .with_label(saved.label);
+            for line in saved.lines {
+                // Sessions saved before lines were numbered leave them in program order.
+                if line.seq > 0 {
+                    self.sequence.set(line.seq - 1);
+                }
+                sequence = sequence.max(self.sequence.get() + 1);
+                let at = std::time::Duration::from_millis(line.time);
+                // Lines are saved as they were kept, so rewrite rules were applied already.
+                let text = redact(&self.redactor, &mut self.redactions, line.text);
+                program.append_line(text, at, &matchers);
             }
+            program.set_outcome(saved.outcome);
+            self.programs.insert(program);
+        }
+        self.sequence.set(sequence);
+
+        Ok(())
+    }
+
+    fn run(&mut self) -> Result<()> {
+        if let Some(path) = &self.opt.compare_with {
+            let previous = session::load(path)?;
+            self.display_options.previous_folds = Some(Arc::new(previous.fold_hashes()));
         }
+        if let Some(dir) = &self.opt.spool {
+            let (interval, keep) = (self.opt.spool_interval, self.opt.spool_keep);
+            self.spool = Some(spool::Spool::new(dir, interval, keep)?);
+        }
+        if let Some(address) = &self.opt.mirror {
+            self.mirror = Some(async_std::task::block_on(address.bind())?);
+        }
+        if let Some(path) = &self.opt.long_lines_file {
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)?;
+            self.long_lines = Some(file);
+        }
+
+        if let Some(cmdline::Command::Open { file }) = &self.opt.command {
+            let session = session::load(file)?;
+            self.restore_session(session)?;
+
+            // Nothing more is coming, but unless debugging, keep the broker open so that the
+            // session stays on screen until the user quits.
+            if self.opt.debug {
+                drop(self.sender.take());
+            }
+        } else if let Some(cmdline::Command::Play { file }) = &self.opt.command {
+            let mut session = session::load(file)?;
+            // The player takes the lines out before `restore_session` could redact them.
+            for line in session
+                .programs
+                .iter_mut()
+                .flat_map(|saved| &mut saved.lines)
+            {
+                let text = std::mem::take(&mut line.text);
+                line.text = redact(&self.redactor, &mut self.redactions, text);
+            }
+            let mut player = play::Player::new(&mut session.programs);
+            self.restore_session(session)?;
+            for (_, program) in &mut self.programs {
+                program.set_outcome(None);
+            }
+            let regex_set = &self.regex_set;
+            player.mark_boundaries(|text| regex_set.is_match(text));
+            self.player = Some(player);
+
+            if self.opt.debug {
+                drop(self.sender.take());
+            }
+        } else if let Some(cmdline::Command::Wrap { command }) = &self.opt.command {
+            let command = command.clone();
+            self.load_match_pairs()?;
+            self.spawn_program(
+                describe_argv(&command),
+                &command,
+                &manifest::Settings::default(),
+                true,
+            )?;
+            drop(self.sender.take());
+        } else if let Some(cmdline::Command::Attach { name }) = &self.opt.command {
+            let (session, stream) = async_std::task::block_on(remote::connect(name))?;
+            self.follow(session, stream)?;
+        } else if let Some(cmdline::Command::View { address }) = &self.opt.command {
+            let (session, stream) = async_std::task::block_on(remote::connect_to(address))?;
+            self.follow(session, stream)?;
+        } else {
+            self.load_match_pairs()?;
+            self.load_programs()?;
+
+            if self.programs.is_empty() {
+                if self.opt.programs_file.is_none() {
+                    self.insert_stdin()?;
+                } else if self.entries.is_none() {
+                    return Err(Error::NoPrograms.into());
+                }
+            }
 
-        drop(self.sender.take());
+            self.release_sender();
+        }
 
-        if !self.opt.replay || self.opt.debug {
+        if self.opt.daemonize {
+            let name = self.opt.session.clone().ok_or(Error::SessionNameRequired)?;
+            async_std::task::block_on(self.serve(&name))?;
+        } else if !self.opt.replay || self.opt.debug {
             async_std::task::block_on(async {
                 let _ = self.run_loop().await;
             });
@@ -202,55 +644,472 @@ impl Main {
             self.end_execution()?;
         }
 
+        if let Some(path) = &self.opt.save {
+            let filter = self.opt.save_filter.as_ref();
+            session::save(path, &self.match_pairs, &self.programs, filter)?;
+        }
+
+        for warning in self.warnings.iter().cloned().chain(self.unmatched()) {
+            eprintln!("warning: {}", warning);
+        }
+
+        if let (true, Some(timeout)) = (self.timed_out, self.opt.total_timeout) {
+            let timeout = self.display_options.format.duration(timeout);
+            return Err(Error::TotalTimeout(timeout).into());
+        }
+
+        // Leave the same way as the wrapped command did.
+        if let Some(cmdline::Command::Wrap { .. }) = &self.opt.command {
+            if let Some(outcome) = self.programs.iter().next().and_then(|(_, p)| p.outcome()) {
+                outcome.exit();
+            }
+        }
+
         Ok(())
     }
 
-    fn add_child_program(&mut self, desc: String, mut child: std::process::Child) -> Result<()> {
-        let stderr = child.stderr.take().unwrap();
-        let stdout = child.stdout.take().unwrap();
+    /// Show a session running elsewhere, and keep up with its updates.
+    fn follow(
+        &mut self,
+        session: session::Session,
+        stream: async_std::io::BufReader<async_std::os::unix::net::UnixStream>,
+    ) -> Result<()> {
+        self.restore_session(session)?;
 
-        let (stderr, stdout) = unsafe {
-            use std::os::unix::io::FromRawFd;
-            use std::os::unix::io::IntoRawFd;
-            let stderr = stderr.into_raw_fd();
-            let stdout = stdout.into_raw_fd();
-            let stderr = async_std::fs::File::from_raw_fd(stderr);
-            let stdout = async_std::fs::File::from_raw_fd(stdout);
-            (stderr, stdout)
-        };
+        let broker_sender = self.sender.take().unwrap();
+        async_std::task::spawn(async move {
+            let _res = remote::relay(stream, broker_sender).await;
+        });
 
-        let entry = self.programs.vacant_entry();
-        let key = entry.key();
-        let mut shutdown_senders = vec![];
+        Ok(())
+    }
 
-        let (_shutdown_sender, shutdown_receiver) = mpsc::unbounded::<()>();
-        shutdown_senders.push(_shutdown_sender);
-        let broker_sender = self.sender.clone().unwrap();
-        async_std::task::spawn(async move {
-            let _res = Self::read_loop(key, broker_sender, shutdown_receiver, stdout).await;
+    /// Returns the sender along with the count of messages it has waiting.
+    fn program_channel(&mut self) -> (Sender<(Key, Input)>, Arc<AtomicUsize>) {
+        let (sender, receiver) = mpsc::unbounded();
+        let backlog = Arc::new(AtomicUsize::new(0));
+        let received = backlog.clone();
+        let receiver = futures::StreamExt::inspect(receiver, move |_| {
+            received.fetch_sub(1, Ordering::Relaxed);
         });
+        self.receivers.push(futures::StreamExt::boxed(receiver));
 
-        let (_shutdown_sender, shutdown_receiver) = mpsc::unbounded::<()>();
-        shutdown_senders.push(_shutdown_sender);
-        let broker_sender = self.sender.clone().unwrap();
-        async_std::task::spawn(async move {
-            let _res = Self::read_loop(key, broker_sender, shutdown_receiver, stderr).await;
+        (sender, backlog)
+    }
+
+    /// Once no more programs can come, let the broker end with the last of them.
+    fn release_sender(&mut self) {
+        if self.entries.is_none() && self.queue.is_empty() {
+            drop(self.sender.take());
+        }
+    }
+
+    fn add_child_program(&mut self, key: Key, mut child: std::process::Child) -> Result<()> {
+        let stderr = pipe_reader(child.stderr.take().unwrap());
+        let stdout = pipe_reader(child.stdout.take().unwrap());
+
+        let mut shutdown_senders = vec![];
+        let reading = self.reading();
+        let prompts = child.stdin.is_some();
+        let (broker_sender, backlog) = self.program_channel();
+
+        for (idx, reader) in vec![stdout, stderr].into_iter().enumerate() {
+            let (_shutdown_sender, shutdown_receiver) = mpsc::unbounded::<()>();
+            shutdown_senders.push(_shutdown_sender);
+            let broker_sender = broker_sender.clone();
+            let backlog = backlog.clone();
+            async_std::task::spawn(async move {
+                let feed = Feed {
+                    sender: broker_sender,
+                    backlog,
+                };
+                let prompts = if prompts { Some(idx) } else { None };
+                let _res =
+                    Self::read_loop(key, feed, shutdown_receiver, reader, reading, prompts).await;
+            });
+        }
+
+        self.programs[key].start(child, shutdown_senders, backlog);
+        Ok(())
+    }
+
+    /// Add a program, with its output going to the broker. It starts right away unless it is
+    /// delayed, either by itself or by `--stagger`. A foreground program shares our stdin and
+    /// process group, like a command run directly from the shell.
+    fn spawn_program(
+        &mut self,
+        desc: String,
+        argv: &[String],
+        settings: &manifest::Settings,
+        foreground: bool,
+    ) -> Result<()> {
+        let key = self.programs.insert(
+            Program::new(desc, vec![])
+                .with_index(self.opt.index)
+                .with_label(settings.label.clone())
+                .with_expect(settings.expect.clone()),
+        );
+
+        let now = std::time::Instant::now();
+        let mut at = now + settings.delay.unwrap_or_default();
+        if let Some(next_start) = self.next_start {
+            at = at.max(next_start);
+        }
+        if let Some(stagger) = self.opt.stagger {
+            self.next_start = Some(at + stagger);
+        }
+
+        if at <= now {
+            return self.launch(key, argv, settings, foreground);
+        }
+
+        self.programs[key].set_starts_at(Some(at));
+        self.queue.push(Queued {
+            key,
+            at,
+            argv: argv.to_vec(),
+            settings: settings.clone(),
+            foreground,
         });
 
-        entry.insert(Program::new(desc, shutdown_senders).with_child(child));
         Ok(())
     }
 
-    fn load_programs(&mut self) -> Result<()> {
+    /// Start the programs whose time has come. Those that cannot be started are told about at
+    /// the end.
+    fn start_due_programs(&mut self) {
+        let now = std::time::Instant::now();
+        let (due, pending) = std::mem::take(&mut self.queue)
+            .into_iter()
+            .partition(|queued| queued.at <= now);
+        self.queue = pending;
+
+        for queued in due {
+            let (key, argv) = (queued.key, &queued.argv);
+            if let Err(err) = self.launch(key, argv, &queued.settings, queued.foreground) {
+                let desc = self.programs[key].desc().to_owned();
+                self.warnings.push(format!("{}: {}", desc, err));
+            }
+        }
+
+        self.release_sender();
+    }
+
+    /// Forget about the programs that did not start yet.
+    fn cancel_queue(&mut self) {
+        for queued in self.queue.drain(..) {
+            self.programs[queued.key].set_starts_at(None);
+        }
+        self.release_sender();
+    }
+
+    /// Kill all programs, along with those that did not start yet.
+    fn kill(&mut self) {
+        self.cancel_queue();
+        for (_, program) in &self.programs {
+            program.signal(libc::SIGKILL);
+        }
+    }
+
+    /// When `--total-timeout` runs out.
+    fn deadline(&self) -> Option<std::time::Instant> {
+        self.opt.total_timeout.map(|timeout| self.start + timeout)
+    }
+
+    /// Whether the screen is drawn on.
+    fn drawing(&self) -> bool {
+        !self.opt.debug && !self.piped && self.announcer.is_none()
+    }
+
+    /// Whether we follow a session running elsewhere, with `attach` or `view`.
+    fn is_remote(&self) -> bool {
+        matches!(
+            self.opt.command,
+            Some(cmdline::Command::Attach { .. } | cmdline::Command::View { .. })
+        )
+    }
+
+    /// When the next `--heartbeat` line is due, if one is needed at all.
+    fn next_heartbeat(&self) -> Option<std::time::Instant> {
+        match self.opt.heartbeat {
+            Some(interval) if self.piped && !self.opt.debug => Some(self.last_heartbeat + interval),
+            _ => None,
+        }
+    }
+
+    /// How long until `handle_timers` has something to do.
+    fn timer_wait(&self) -> Option<std::time::Duration> {
+        let now = std::time::Instant::now();
+        let queue = self.queue.iter().map(|queued| queued.at);
+        let deadline = self.deadline().filter(|_| !self.timed_out);
+
+        let playback = self.player.as_ref().and_then(|player| player.next_due());
+        let spool = self.spool.as_ref().map(|spool| spool.due_at());
+
+        queue
+            .chain(deadline)
+            .chain(self.next_heartbeat())
+            .chain(playback)
+            .chain(spool)
+            .map(|at| at.saturating_duration_since(now))
+            .min()
+    }
+
+    /// Once a second, update the line rates shown in the titles of the programs.
+    fn sample_rates(&mut self) {
+        let now = std::time::Instant::now();
+        if now < self.last_rate_sample + std::time::Duration::from_secs(1) {
+            return;
+        }
+
+        for (_, program) in &mut self.programs {
+            program.sample_rate(now);
+        }
+        self.last_rate_sample = now;
+    }
+
+    /// Show the events of the saved session that playback went through.
+    fn play(&mut self, seek: play::Seek) {
+        let events = match seek {
+            play::Seek::Forward(events) => events,
+            play::Seek::Rewind(events) => {
+                for (_, program) in &mut self.programs {
+                    let desc = program.desc().to_owned();
+                    let label = program.label().map(str::to_owned);
+                    *program = Program::new(desc, vec![])
+                        .with_index(self.opt.index)
+                        .with_label(label);
+                }
+                if let Some(spool) = &mut self.spool {
+                    for (key, _) in &self.programs {
+                        spool.resend(key);
+                    }
+                }
+                self.display_options.width_cache.borrow_mut().clear();
+                events
+            }
+        };
+
+        let player = match &self.player {
+            Some(player) => player,
+            None => return,
+        };
+        let events: Vec<_> = events.map(|idx| player.event(idx)).collect();
+        let outcomes = if player.is_finished() {
+            player.outcomes().to_vec()
+        } else {
+            vec![None; player.outcomes().len()]
+        };
+
+        for (key, at, text) in events {
+            self.handle_input(key, Input::Remote(at, text), at);
+        }
+        for (key, outcome) in outcomes.into_iter().enumerate() {
+            if let Some(program) = self.programs.get_mut(key) {
+                program.set_outcome(outcome);
+            }
+        }
+    }
+
+    /// Controls of `play`. Returns whether the key was one of them.
+    fn handle_playback_key(&mut self, key: event::Key) -> bool {
+        let player = match &mut self.player {
+            Some(player) => player,
+            None => return false,
+        };
+
+        let seek = match key {
+            event::Key::Char(' ') => {
+                player.toggle_pause();
+                return true;
+            }
+            event::Key::Char('.') => player.step(),
+            event::Key::Right => player.next_boundary(),
+            event::Key::Left => player.previous_boundary(),
+            event::Key::Char(c @ '0'..='9') => player.seek_tenth(c as u32 - '0' as u32),
+            _ => return false,
+        };
+        self.play(seek);
+
+        true
+    }
+
+    /// Let CI systems that watch for inactivity know that we are not stuck, while nothing is
+    /// drawn.
+    fn heartbeat(&mut self) {
+        for (_, program) in &mut self.programs {
+            program.collect_outcome(false);
+        }
+
+        let running: Vec<_> = self
+            .programs
+            .iter()
+            .filter(|(_, program)| program.child.is_some() && program.outcome().is_none())
+            .map(|(_, program)| program.current_fold().unwrap_or_else(|| program.desc()))
+            .collect();
+
+        let ellipsis = if self.display_options.ascii {
+            "..."
+        } else {
+            "…"
+        };
+        let elapsed = self.display_options.format.duration(self.start.elapsed());
+        if running.is_empty() {
+            eprintln!("{} still running, {}", ellipsis, elapsed);
+        } else {
+            eprintln!(
+                "{} still running: {}, {}",
+                ellipsis,
+                running.join(", "),
+                elapsed
+            );
+        }
+
+        self.last_heartbeat = std::time::Instant::now();
+    }
+
+    /// Start the queued programs that are due, and kill everything once the total timeout
+    /// runs out. Returns whether the latter happened just now.
+    fn handle_timers(&mut self) -> Result<bool> {
+        self.start_due_programs();
+
+        if let Some(due) = self.player.as_mut().map(|player| player.take_due()) {
+            self.play(play::Seek::Forward(due));
+        }
+
+        if let Some(heartbeat) = self.next_heartbeat() {
+            if std::time::Instant::now() >= heartbeat {
+                self.heartbeat();
+            }
+        }
+
+        match self.deadline() {
+            Some(deadline) if !self.timed_out && std::time::Instant::now() >= deadline => {
+                self.timed_out = true;
+                self.kill();
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    fn launch(
+        &mut self,
+        key: Key,
+        argv: &[String],
+        settings: &manifest::Settings,
+        foreground: bool,
+    ) -> Result<()> {
+        use std::os::unix::process::CommandExt;
+
+        let mut command = settings.command(argv);
+
+        // Own process group, so that signals can be sent to the whole tree of each program.
+        if !foreground {
+            unsafe {
+                command.pre_exec(|| {
+                    if libc::setpgid(0, 0) != 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                    Ok(())
+                });
+            }
+        }
+
+        let stdin = if foreground {
+            std::process::Stdio::inherit()
+        } else if !settings.expect.is_empty() {
+            std::process::Stdio::piped()
+        } else {
+            std::process::Stdio::null()
+        };
+
+        let spawned = command
+            .stdin(stdin)
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped())
+            .spawn();
+        let child = match spawned {
+            Ok(child) => child,
+            Err(err) => {
+                // Ended as with a shell that cannot run it, rather than left to look running.
+                let program = &mut self.programs[key];
+                program.set_starts_at(None);
+                program.set_outcome(Some(program::Outcome::Exited(127)));
+                return Err(err.into());
+            }
+        };
+
+        self.add_child_program(key, child)
+    }
+
+    /// Run a command from the programs file with the shell, with its `{index}` and `{label}`
+    /// placeholders expanded.
+    fn spawn_entry(&mut self, entry: manifest::Entry) -> Result<()> {
         let std = "/bin/sh".to_owned();
         let shell = self.opt.shell.clone().unwrap_or(std);
 
+        let template = entry.settings.template(self.programs.vacant_entry().key());
+        let settings = entry.settings.expand(&template)?;
+        let command = template.expand(&entry.command);
+
+        let argv = [shell, "-c".to_owned(), command.clone()];
+        self.spawn_program(command, &argv, &settings, false)
+    }
+
+    /// Parse programs from stdin in a dedicated thread, so that each one can be started as
+    /// soon as it arrives.
+    fn read_entries_live(&mut self) {
+        let (sender, receiver) = mpsc::unbounded();
+
+        std::thread::spawn(move || {
+            let mut parser = manifest::Parser::default();
+            for line in std::io::BufReader::new(std::io::stdin()).lines() {
+                // Entries that cannot be parsed are reported, and reading goes on past them.
+                let failed = line.is_err();
+                let entry = line.map_err(Into::into).and_then(|line| parser.push(line));
+                if let Some(entry) = entry.transpose() {
+                    if sender.unbounded_send(entry).is_err() || failed {
+                        return;
+                    }
+                }
+            }
+        });
+
+        self.entries = Some(receiver);
+    }
+
+    /// Handle the next program arriving from stdin, or the end of them. Entries that cannot
+    /// be parsed or started are told about at the end, without stopping the others.
+    fn handle_entry(&mut self, entry: Option<Result<manifest::Entry>>) {
+        match entry {
+            Some(Ok(entry)) => {
+                let command = entry.command.clone();
+                if let Err(err) = self.spawn_entry(entry) {
+                    self.warnings.push(format!("{}: {}", command, err));
+                }
+            }
+            Some(Err(err)) => self.warnings.push(err.to_string()),
+            None => {
+                self.entries = None;
+                self.release_sender();
+            }
+        }
+    }
+
+    fn load_programs(&mut self) -> Result<()> {
         if let Some(pathname) = &self.opt.programs_file {
             let mut lines = vec![];
 
             if pathname == "-" {
-                for line in std::io::BufReader::new(std::io::stdin()).lines() {
-                    lines.push(line);
+                // Background sessions take the whole list up front, as their clients only
+                // learn about programs from the initial snapshot.
+                if !self.opt.daemonize {
+                    self.read_entries_live();
+                } else {
+                    for line in std::io::BufReader::new(std::io::stdin()).lines() {
+                        lines.push(line);
+                    }
                 }
             } else {
                 let file = File::open(pathname)?;
@@ -259,16 +1118,11 @@ impl Main {
                 }
             };
 
+            let mut parser = manifest::Parser::default();
             for line in lines.drain(..) {
-                let line = line?;
-                let child = std::process::Command::new(shell.clone())
-                    .stdin(std::process::Stdio::null())
-                    .stdout(std::process::Stdio::piped())
-                    .stderr(std::process::Stdio::piped())
-                    .arg("-c")
-                    .arg(&line)
-                    .spawn()?;
-                self.add_child_program(line, child)?;
+                if let Some(entry) = parser.push(line?)? {
+                    self.spawn_entry(entry)?;
+                }
             }
         }
 
@@ -280,10 +1134,10 @@ impl Main {
         let mut cmnds = vec![];
 
         for arg in &self.opt.programs {
-            if let Some(r) = RE.captures(&arg) {
+            if let Some(r) = RE.captures(arg) {
                 let length = r.get(1).unwrap().as_str().len();
                 if length == 1 {
-                    cmnds.push(std::mem::replace(&mut next_cmd, vec![]));
+                    cmnds.push(std::mem::take(&mut next_cmd));
                     continue;
                 }
 
@@ -298,68 +1152,143 @@ impl Main {
             cmnds.push(next_cmd);
         }
 
-        for cmnd in cmnds.drain(..) {
-            let child = std::process::Command::new(&cmnd[0])
-                .stdin(std::process::Stdio::null())
-                .stdout(std::process::Stdio::piped())
-                .stderr(std::process::Stdio::piped())
-                .args(&cmnd[1..])
-                .spawn()?;
+        if !self.opt.exec.is_empty() {
+            cmnds.push(self.opt.exec.clone());
+        }
 
-            use itertools::Itertools;
-            let mut vec = cmnd.iter().map(|s| shell_escape::escape(s.as_str().into()));
-            self.add_child_program(vec.join(" "), child)?;
+        for cmnd in cmnds.drain(..) {
+            let desc = describe_argv(&cmnd);
+            self.spawn_program(desc, &cmnd, &manifest::Settings::default(), false)?;
         }
 
         Ok(())
     }
 
     fn insert_stdin(&mut self) -> Result<()> {
-        let entry = self.programs.vacant_entry();
-        let key = entry.key();
-        let broker_sender = self.sender.clone().unwrap();
+        let key = self.programs.vacant_entry().key();
+        let (broker_sender, backlog) = self.program_channel();
+        let feed = Feed {
+            sender: broker_sender,
+            backlog: backlog.clone(),
+        };
         let (_shutdown_sender, shutdown_receiver) = mpsc::unbounded::<()>();
         let mut shutdown_senders = vec![];
+        let reading = self.reading();
 
         async_std::task::spawn(async move {
             let _res = Self::read_loop(
                 key,
-                broker_sender,
+                feed,
                 shutdown_receiver,
                 async_std::io::stdin(),
+                reading,
+                None,
             )
             .await;
         });
 
         shutdown_senders.push(_shutdown_sender);
-        entry.insert(Program::new("<<stdin>>".to_owned(), shutdown_senders));
+        self.programs.insert(
+            Program::new("<<stdin>>".to_owned(), shutdown_senders)
+                .with_index(self.opt.index)
+                .with_backlog(backlog),
+        );
 
         Ok(())
     }
 
+    fn reading(&self) -> Reading {
+        Reading {
+            invalid_utf8: self.opt.invalid_utf8,
+            // The file gets lines in full, so the broker cuts them after writing them there.
+            max_line_length: match self.long_lines {
+                Some(_) => 0,
+                None => self.opt.max_line_length,
+            },
+            format: self.display_options.format,
+        }
+    }
+
+    /// Send what `reader` outputs to the broker line by line. With `prompts`, the index of the
+    /// reader among those of the program, an incomplete line is also sent once output pauses
+    /// on it, so that it can be answered.
     async fn read_loop<R>(
         key: Key,
-        mut sender: Sender<(Key, Result<Text, std::io::Error>)>,
+        mut sender: Feed,
         mut receiver: Receiver<()>,
-        reader: R,
+        mut reader: R,
+        reading: Reading,
+        prompts: Option<usize>,
     ) -> Result<()>
     where
         R: futures::AsyncRead + Unpin,
     {
-        use async_std::io::BufReader;
         use async_std::prelude::*;
 
-        let mut lines = BufReader::new(reader).lines();
+        let mut chunk = vec![0; 0x10000];
+        // Read but not yet sent, lacking a line ending.
+        let mut partial = PartialLine::default();
+        // Whether `partial` grew since it was last sent as a prompt, and whether it was at all.
+        let (mut grown, mut prompted) = (false, false);
+        let max = reading.max_line_length;
+
+        let line = |partial: &PartialLine, prompted: bool| {
+            let text = partial.text(&reading);
+            match prompts {
+                Some(reader) if prompted => Input::Prompt {
+                    reader,
+                    text,
+                    complete: true,
+                },
+                _ => Input::Line(Ok(text)),
+            }
+        };
 
         loop {
+            let pause = async {
+                match prompts {
+                    Some(_) if grown => async_std::task::sleep(PROMPT_PAUSE).await,
+                    _ => async_std::future::pending().await,
+                }
+            };
+
             futures::select! {
-                line = lines.next().fuse() => match line {
-                    Some(Ok(s)) => sender.send((key, Ok(s))).await?,
-                    Some(Err(err)) => {
-                        sender.send((key, Err(err))).await?;
+                read = reader.read(&mut chunk).fuse() => match read {
+                    Ok(0) => {
+                        if partial.len > 0 {
+                            sender.send((key, line(&partial, prompted))).await?
+                        }
                         break;
                     }
-                    None => break,
+                    Ok(size) => {
+                        let mut rest = &chunk[..size];
+                        while let Some(pos) = rest.iter().position(|b| *b == b'\n') {
+                            partial.extend(&rest[..pos], max);
+                            sender.send((key, line(&partial, prompted))).await?;
+                            partial.clear();
+                            prompted = false;
+                            rest = &rest[pos + 1..];
+                        }
+                        partial.extend(rest, max);
+                        grown = !rest.is_empty();
+                    }
+                    Err(err) => {
+                        sender.send((key, Input::Line(Err(err)))).await?;
+                        break;
+                    }
+                },
+                _ = pause.fuse() => {
+                    if let Some(reader) = prompts {
+                        let text = reading.invalid_utf8.decode(&partial.buf);
+                        let prompt = Input::Prompt {
+                            reader,
+                            text,
+                            complete: false,
+                        };
+                        sender.send((key, prompt)).await?;
+                        grown = false;
+                        prompted = true;
+                    }
                 },
                 shutdown = receiver.next().fuse() => match shutdown {
                     Some(_) => break,
@@ -371,50 +1300,399 @@ impl Main {
         Ok(())
     }
 
-    async fn run_loop(&mut self) -> Result<()> {
-        use async_std::stream::StreamExt;
+    /// Take the secrets out of a line that arrived, before anything else sees it.
+    fn redact_input(&mut self, input: Input) -> Input {
+        match input {
+            Input::Line(Ok(s)) => Input::Line(Ok(redact(&self.redactor, &mut self.redactions, s))),
+            Input::Remote(at, s) => {
+                Input::Remote(at, redact(&self.redactor, &mut self.redactions, s))
+            }
+            Input::Prompt {
+                reader,
+                text,
+                complete,
+            } => Input::Prompt {
+                reader,
+                text: redact(&self.redactor, &mut self.redactions, text),
+                complete,
+            },
+            input => input,
+        }
+    }
+
+    /// Make what arrived for a program fit to be passed on.
+    fn receive(&mut self, key: Key, input: Input) -> Input {
+        let input = self.redact_input(input);
+        self.truncate_input(key, input)
+    }
+
+    /// Cut a line that the reader kept in full for `--long-lines-file`, after writing it there.
+    fn truncate_input(&mut self, key: Key, mut input: Input) -> Input {
+        let max = self.opt.max_line_length;
+        let (file, program) = match (&mut self.long_lines, self.programs.get(key)) {
+            (Some(file), Some(program)) => (file, program),
+            _ => return input,
+        };
+
+        if let Some(s) = input.line_mut() {
+            if max > 0 && s.len() > max {
+                let nr = program.arrivals().len() + 1;
+                // Losing the full text is no reason to stop.
+                let _ = writeln!(file, "==> {}, line {} <==\n{}", program.desc(), nr, s);
+                *s = truncate(s, max, s.len(), &self.display_options.format);
+            }
+        }
+
+        input
+    }
+
+    /// Apply what the broker received for a program. Lines read locally are taken to have
+    /// arrived `at` the given time.
+    fn handle_input(&mut self, key: Key, input: Input, at: std::time::Duration) {
+        if let Input::RemoteProgram(desc, label) = input {
+            // Clients may hear of a program that their snapshot had already.
+            if self.programs.vacant_entry().key() == key {
+                let program = Program::new(desc, vec![])
+                    .with_index(self.opt.index)
+                    .with_label(label);
+                self.programs.insert(program);
+            }
+            return;
+        }
+
         let matchers = Matchers {
             match_pairs: &self.match_pairs,
             regex_set: &self.regex_set,
+            sequence: &self.sequence,
+        };
+        let program = match self.programs.get_mut(key) {
+            Some(program) => program,
+            None => return,
+        };
+
+        let rules = &self.opt.rewrite;
+        let (s, at) = match input {
+            Input::Line(Ok(s)) => {
+                program.respond(&s);
+                if !rules.is_empty() {
+                    program.keep_original(&s);
+                }
+                (s, at)
+            }
+            Input::Prompt {
+                reader,
+                text,
+                complete,
+            } => {
+                program.respond_prompt(reader, &text, complete);
+                if !complete {
+                    return;
+                }
+                if !rules.is_empty() {
+                    program.keep_original(&text);
+                }
+                (text, at)
+            }
+            Input::Line(Err(_)) => return,
+            Input::Remote(at, s) => (s, at),
+            Input::RemoteProgram(..) => return,
+            Input::RemoteOutcome(outcome) => {
+                program.set_outcome(Some(outcome));
+                return;
+            }
+        };
+
+        let s = rewrite::apply_all(rules, s);
+        let error = self.opt.error_regex.is_match(&s);
+        if error {
+            program.note_error();
+        }
+        let error = if error { Some(s.clone()) } else { None };
+        let kept = program.arrivals().len();
+        let spooled = self.spool.as_ref().map(|_| s.clone());
+        let change = program.append_line(s, at, &matchers);
+        if let (Some(spool), Some(text)) = (&mut self.spool, spooled) {
+            if program.arrivals().len() > kept {
+                spool.line(session::last_line(program, key, text));
+            }
+        }
+
+        if let (Some(spool), Some(_)) = (&mut self.spool, &change) {
+            spool.significant();
+        }
+
+        if let Some(announcer) = &mut self.announcer {
+            let desc = program.desc();
+            match change {
+                Some(program::FoldChange::Started(title)) => {
+                    announcer.announce(format!("{}: started {}", desc, title))
+                }
+                Some(program::FoldChange::Ended(title)) => {
+                    announcer.announce(format!("{}: finished {}", desc, title))
+                }
+                None => {}
+            }
+            if let Some(error) = error {
+                announcer.announce(format!("{}: error: {}", desc, error.trim()));
+            }
+        }
+    }
+
+    /// Print the pending announcements, after checking for programs that finished. Unless
+    /// forced, this waits for the interval of `--announce` to pass.
+    fn announce(&mut self, force: bool) {
+        let announcer = match &mut self.announcer {
+            Some(announcer) => announcer,
+            None => return,
+        };
+
+        for (key, program) in &mut self.programs {
+            program.collect_outcome(false);
+            if let Some(outcome) = program.outcome() {
+                announcer.finished(key, format!("{}: {}", program.desc(), outcome));
+            }
+        }
+
+        for line in announcer.flush(force) {
+            println!("{}", line);
+        }
+    }
+
+    /// Write a snapshot of the session with `--spool` if one is due. Forcing one writes the
+    /// last, waiting for it. Should writing fail, spooling stops, to be told about at the end.
+    fn spool(&mut self, force: bool) {
+        let spool = match &mut self.spool {
+            Some(spool) => spool,
+            None => return,
         };
 
-        if !self.opt.debug {
+        for (key, program) in &mut self.programs {
+            program.collect_outcome(false);
+            spool.program(key, program.outcome().is_some());
+        }
+        if !force && !spool.is_due() {
+            return;
+        }
+
+        let programs = &self.programs;
+        let update = spool.update(
+            session::pairs(&self.match_pairs),
+            |key| {
+                let program = programs.get(key)?;
+                Some(session::SessionProgram {
+                    folds: session::folds(program, key, |_| true),
+                    ..session::header(program)
+                })
+            },
+            |key| session::lines(&programs[key], key, |_| true),
+        );
+        let res = if force {
+            spool.finish(Some(update))
+        } else {
+            spool.write(update)
+        };
+
+        if let Err(err) = res {
+            self.spool = None;
+            self.warnings.push(format!("stopped spooling: {}", err));
+        }
+    }
+
+    /// Run without a terminal, feeding the session to the clients attaching to it.
+    async fn serve(&mut self, name: &str) -> Result<()> {
+        use async_std::os::unix::net::UnixListener;
+        use async_std::stream::StreamExt;
+
+        let path = remote::socket_path(name)?;
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).await?;
+        let mut clients = remote::Clients::default();
+
+        loop {
+            self.spool(false);
+
+            let wait = self.timer_wait();
+            let timer = async {
+                match wait {
+                    Some(wait) => async_std::task::sleep(wait).await,
+                    None => async_std::future::pending().await,
+                }
+            };
+
+            futures::select! {
+                _ = timer.fuse() => {
+                    // Killed children end their output, which ends the loop.
+                    self.handle_timers()?;
+                },
+                r = self.receivers.next().fuse() => match r {
+                    Some((key, input)) => {
+                        let at = self.start.elapsed();
+                        let input = self.receive(key, input);
+                        self.broadcast(&mut clients, key, &input, at)?;
+                        self.handle_input(key, input, at);
+                    }
+                    None => break,
+                },
+                accepted = listener.accept().fuse() => {
+                    if let Ok((stream, _)) = accepted {
+                        self.add_client(&mut clients, stream)?;
+                    }
+                },
+            }
+        }
+
+        for (_, program) in &mut self.programs {
+            program.collect_outcome(true);
+        }
+        self.spool(true);
+        self.send_outcomes(&mut clients)?;
+
+        // Keep the results around until someone gets to see them.
+        if clients.is_empty() {
+            if let Ok((stream, _)) = listener.accept().await {
+                self.add_client(&mut clients, stream)?;
+            }
+        }
+
+        let _ = std::fs::remove_file(&path);
+        clients.finish().await;
+
+        Ok(())
+    }
+
+    fn snapshot(&self) -> session::Session {
+        session::snapshot(&self.match_pairs, &self.programs)
+    }
+
+    /// Start feeding a client, after telling the others of the programs in its snapshot.
+    fn add_client(
+        &self,
+        clients: &mut remote::Clients,
+        stream: async_std::os::unix::net::UnixStream,
+    ) -> Result<()> {
+        self.send_programs(clients)?;
+        clients.add(stream, self.snapshot())
+    }
+
+    /// Tell the clients of the programs added since they last heard of any, as lines of
+    /// programs they do not know of would be lost on them.
+    fn send_programs(&self, clients: &mut remote::Clients) -> Result<()> {
+        for (key, program) in self.programs.iter().skip(clients.known) {
+            clients.send(&remote::Message::Program {
+                program: key,
+                desc: program.desc().to_owned(),
+                label: program.label().map(str::to_owned),
+            })?;
+        }
+        clients.known = self.programs.len();
+
+        Ok(())
+    }
+
+    /// Pass a line that arrived on to the clients, rewritten as it is kept here.
+    fn broadcast(
+        &self,
+        clients: &mut remote::Clients,
+        key: Key,
+        input: &Input,
+        at: std::time::Duration,
+    ) -> Result<()> {
+        if let (Some(text), false) = (input.line(), clients.is_empty()) {
+            self.send_programs(clients)?;
+            let text = rewrite::apply_all(&self.opt.rewrite, text.clone());
+            clients.send(&remote::Message::line(key, at, &text))?;
+        }
+
+        Ok(())
+    }
+
+    fn send_outcomes(&self, clients: &mut remote::Clients) -> Result<()> {
+        self.send_programs(clients)?;
+        for (key, program) in &self.programs {
+            if let Some(outcome) = program.outcome() {
+                clients.send(&remote::Message::Outcome {
+                    program: key,
+                    outcome,
+                })?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Pass Ctrl+C on to the programs that the terminal does not send it to by itself.
+    fn interrupt(&self) {
+        for (_, program) in &self.programs {
+            if !program.in_terminal_foreground() {
+                program.signal(libc::SIGINT);
+            }
+        }
+    }
+
+    async fn run_loop(&mut self) -> Result<()> {
+        use async_std::stream::StreamExt;
+
+        if self.drawing() {
             println!("{}", termion::cursor::Hide);
             println!("{}", termion::clear::All);
         }
 
+        let (key_sender, mut key_receiver) = mpsc::unbounded();
+        // A wrapped command keeps the terminal to itself, as it may be reading from it.
+        let wrapped = matches!(self.opt.command, Some(cmdline::Command::Wrap { .. }));
+        let tty = if !self.drawing() || wrapped {
+            None
+        } else {
+            // Ctrl+\ detaches from a remote session, and otherwise keeps its usual meaning.
+            input::Tty::open(self.is_remote()).ok()
+        };
+        if let Some(tty) = &tty {
+            tty.spawn_reader(key_sender.clone())?;
+        }
+
         let ctrlc = async_ctrlc::CtrlC::new().expect("cannot create Ctrl+C handler?");
-        let mut ctrlc_stream = ctrlc.enumerate().take(3);
+        let mut ctrlc_stream = ctrlc.enumerate();
         let mut stdout = BufWriter::with_capacity(0x10000, stdout());
         let mut last_redraw_time = std::time::Instant::now();
         let mut need_redraw = false;
         let min_refresh_time = std::time::Duration::from_millis(4);
 
+        let mirror = self.mirror.take();
+        let mut viewers = remote::Clients::default();
+
         loop {
+            self.sample_rates();
+            self.announce(false);
+            self.spool(false);
+
             let never = async_std::future::pending::<()>();
             let dur = if need_redraw {
                 min_refresh_time
             } else {
                 std::time::Duration::from_millis(1000)
             };
+            let dur = self.timer_wait().map_or(dur, |wait| wait.min(dur));
 
             futures::select! {
                 timeout = async_std::future::timeout(dur, never).fuse() => {
+                    if self.handle_timers()? {
+                        break;
+                    }
                     let now = std::time::Instant::now();
-                    if last_redraw_time + min_refresh_time <= now {
+                    if self.drawing() && last_redraw_time + min_refresh_time <= now {
                         self.redraw(DrawMode::Ongoing, &mut stdout)?;
                         last_redraw_time = now;
                         need_redraw = false
                     }
                 },
-                r = self.receiver.next().fuse() => match r {
-                    Some((key, item)) => {
-                        if let Ok(s) = item {
-                            let program = &mut self.programs[key];
-                            program.append_line(s, &matchers);
-                        }
-
-                        if !self.opt.debug {
+                r = self.receivers.next().fuse() => match r {
+                    Some((key, input)) => {
+                        let at = self.start.elapsed();
+                        let input = self.receive(key, input);
+                        self.broadcast(&mut viewers, key, &input, at)?;
+                        self.handle_input(key, input, at);
+
+                        if self.drawing() {
                             let now = std::time::Instant::now();
                             if last_redraw_time + min_refresh_time <= now {
                                 self.redraw(DrawMode::Ongoing, &mut stdout)?;
@@ -432,38 +1710,525 @@ impl Main {
                     },
                     None => break,
                 },
+                key = key_receiver.next().fuse() => match key {
+                    Some(key) => {
+                        if self.handle_key(key) {
+                            self.kill();
+                            break;
+                        }
+                        self.redraw(DrawMode::Ongoing, &mut stdout)?;
+                        last_redraw_time = std::time::Instant::now();
+                    }
+                    None => { }
+                },
+                accepted = remote::accept(&mirror).fuse() => {
+                    if let Ok(stream) = accepted {
+                        self.add_client(&mut viewers, stream)?;
+                    }
+                },
+                entry = next_entry(&mut self.entries).fuse() => {
+                    self.handle_entry(entry);
+                    if self.drawing() {
+                        self.redraw(DrawMode::Ongoing, &mut stdout)?;
+                        last_redraw_time = std::time::Instant::now();
+                    }
+                },
                 ctrlc = ctrlc_stream.next().fuse() => match ctrlc {
-                    Some(_) => break,
+                    Some((0, _)) if self.programs.iter().any(|(_, p)| p.child.is_some()) => {
+                        // Ask nicely first, and keep showing whatever they print while
+                        // shutting down.
+                        self.cancel_queue();
+                        self.interrupt();
+                        self.terminating = true;
+                        if self.drawing() {
+                            self.redraw(DrawMode::Ongoing, &mut stdout)?;
+                        }
+                    }
+                    Some(_) => {
+                        self.kill();
+                        break;
+                    }
                     None => { }
                 },
             }
         }
 
-        if !self.opt.debug {
+        drop(tty);
+        drop(key_sender);
+
+        if self.drawing() {
             self.redraw(DrawMode::Final, &mut stdout)?;
             println!("{}", termion::cursor::Show);
         }
 
         for (_, program) in &mut self.programs {
             program.shutdown().await;
+            program.collect_outcome(true);
+        }
+        self.announce(true);
+        self.spool(true);
+
+        if let Some(address) = &self.opt.mirror {
+            self.send_outcomes(&mut viewers)?;
+            address.remove();
+            viewers.finish().await;
         }
 
         Ok(())
     }
 
+    /// Act on a key press. Returns whether the user asked to quit.
+    fn handle_key(&mut self, key: event::Key) -> bool {
+        if let Some(scroll) = self.help {
+            // Lines are cut rather than wrapped, so there are as many at any width.
+            let lines = self.help_description(0).lines().len();
+            self.help = match key {
+                event::Key::Esc | event::Key::Char('q') | event::Key::Char('?') => None,
+                event::Key::Up | event::Key::Char('k') => Some(scroll.saturating_sub(1)),
+                event::Key::Down | event::Key::Char('j') => Some((scroll + 1).min(lines)),
+                event::Key::PageUp => Some(scroll.saturating_sub(10)),
+                event::Key::PageDown => Some((scroll + 10).min(lines)),
+                _ => Some(scroll),
+            };
+            return false;
+        }
+
+        if let Some(diff_view) = &mut self.diff_view {
+            match key {
+                event::Key::Esc | event::Key::Char('q') | event::Key::Char('d') => {
+                    self.diff_view = None;
+                }
+                event::Key::Up | event::Key::Char('k') => {
+                    diff_view.scroll = diff_view.scroll.saturating_sub(1);
+                }
+                event::Key::Down | event::Key::Char('j') => {
+                    diff_view.scroll = (diff_view.scroll + 1).min(diff_view.lines.len());
+                }
+                event::Key::PageUp => {
+                    diff_view.scroll = diff_view.scroll.saturating_sub(10);
+                }
+                event::Key::PageDown => {
+                    diff_view.scroll = (diff_view.scroll + 10).min(diff_view.lines.len());
+                }
+                _ => {}
+            }
+            return false;
+        }
+
+        if !self.filter_editing && self.handle_playback_key(key) {
+            return false;
+        }
+
+        let remote = self.is_remote();
+        let options = &mut self.display_options;
+
+        if self.filter_editing {
+            match key {
+                event::Key::Char('\n') => {
+                    self.filter_editing = false;
+                    if options.filter.as_deref() == Some("") {
+                        options.filter = None;
+                    }
+                }
+                event::Key::Esc => {
+                    self.filter_editing = false;
+                    options.filter = None;
+                }
+                event::Key::Backspace => {
+                    if let Some(filter) = &mut options.filter {
+                        filter.pop();
+                    }
+                }
+                event::Key::Char(c) => {
+                    if let Some(filter) = &mut options.filter {
+                        filter.push(c);
+                    }
+                }
+                _ => {}
+            }
+            return false;
+        }
+
+        match key {
+            event::Key::Char('q') => return true,
+            // Ctrl+\, for detaching from a session.
+            event::Key::Ctrl('4') if remote => return true,
+            event::Key::Char('/') => {
+                self.filter_editing = true;
+                options.filter = Some(String::new());
+            }
+            event::Key::Esc => {
+                options.filter = None;
+            }
+            event::Key::Char('\t') => {
+                let keys: Vec<Key> = self.programs.iter().map(|(key, _)| key).collect();
+                let pos = self
+                    .selected
+                    .and_then(|selected| keys.iter().position(|key| *key == selected));
+                self.selected = match pos {
+                    Some(pos) => keys.get(pos + 1).copied(),
+                    None => keys.first().copied(),
+                };
+                self.fold_cursor = None;
+            }
+            event::Key::Char(c @ '[') | event::Key::Char(c @ ']') => {
+                if self.selected.is_none() {
+                    self.selected = self.programs.iter().next().map(|(key, _)| key);
+                }
+                if let Some(selected) = self.selected {
+                    let paths = self.programs[selected].fold_paths();
+                    let pos = self
+                        .fold_cursor
+                        .as_ref()
+                        .and_then(|cursor| paths.iter().position(|path| path == cursor));
+                    let pos = match (c, pos) {
+                        (']', Some(pos)) => (pos + 1).min(paths.len().saturating_sub(1)),
+                        ('[', Some(pos)) => pos.saturating_sub(1),
+                        (']', None) => 0,
+                        (_, None) => paths.len().saturating_sub(1),
+                        _ => unreachable!(),
+                    };
+                    self.fold_cursor = paths.get(pos).cloned();
+                }
+            }
+            event::Key::Char('m') => {
+                if let (Some(selected), Some(cursor)) = (self.selected, &self.fold_cursor) {
+                    let mark = (selected, cursor.clone());
+                    if let Some(pos) = self.marked.iter().position(|marked| *marked == mark) {
+                        self.marked.remove(pos);
+                    } else {
+                        if self.marked.len() == 2 {
+                            self.marked.remove(0);
+                        }
+                        self.marked.push(mark);
+                    }
+                }
+            }
+            event::Key::Char('d') => {
+                self.diff_view = self.diff_marked();
+            }
+            event::Key::Char('p') => {
+                if let Some(selected) = self.selected {
+                    if let Some(pos) = self.pinned.iter().position(|key| *key == selected) {
+                        self.pinned.remove(pos);
+                    } else {
+                        if self.pinned.len() == 2 {
+                            self.pinned.remove(0);
+                        }
+                        self.pinned.push(selected);
+                    }
+                }
+            }
+            event::Key::Char('P') => {
+                self.pinned.clear();
+            }
+            event::Key::Char('?') => {
+                self.help = Some(0);
+            }
+            _ => {}
+        }
+
+        false
+    }
+
+    /// Lines nested in a fold, along with its title.
+    fn fold_lines(&self, key: Key, path: &[usize]) -> Option<(&str, Vec<&str>)> {
+        let encapsulation = self.programs.get(key)?.fold(path)?;
+        let mut lines = vec![];
+        program::visit_lines(&encapsulation.content, &mut |line: &Text| {
+            lines.push(line.as_str())
+        });
+        Some((encapsulation.start_title.as_str(), lines))
+    }
+
+    fn diff_marked(&self) -> Option<DiffView> {
+        if let [(a_key, a_path), (b_key, b_path)] = self.marked.as_slice() {
+            let (a_title, a_lines) = self.fold_lines(*a_key, a_path)?;
+            let (b_title, b_lines) = self.fold_lines(*b_key, b_path)?;
+            Some(DiffView {
+                title: format!("{} vs. {}", a_title, b_title),
+                lines: diff::unified(&a_lines, &b_lines, 3),
+                scroll: 0,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Folds to highlight in a program: the cursor and the marked ones.
+    fn highlights(&self, key: Key) -> Vec<&[usize]> {
+        let mut highlights: Vec<&[usize]> = self
+            .marked
+            .iter()
+            .filter(|(marked_key, _)| *marked_key == key)
+            .map(|(_, path)| path.as_slice())
+            .collect();
+
+        if self.selected == Some(key) {
+            if let Some(cursor) = &self.fold_cursor {
+                highlights.push(cursor);
+            }
+        }
+
+        highlights
+    }
+
+    fn status_line(&self, draw_mode: &DrawMode) -> Option<String> {
+        if let DrawMode::Final = draw_mode {
+            return self.summary();
+        }
+
+        if self.terminating {
+            return Some("Waiting for programs to terminate, Ctrl+C again to kill them".into());
+        }
+
+        if let Some(filter) = &self.display_options.filter {
+            if self.filter_editing {
+                return Some(format!("/{}", filter));
+            } else {
+                return Some(format!("Filter: {} (Esc to clear)", filter));
+            }
+        }
+
+        if self.help.is_some() {
+            return Some("Help (arrows to scroll, Esc to close)".into());
+        }
+
+        if self.diff_view.is_some() {
+            return Some(
+                "Differences between marked folds (arrows to scroll, Esc to close)".into(),
+            );
+        }
+
+        let format = &self.display_options.format;
+        if let Some(player) = &self.player {
+            return Some(format!(
+                "{} / {}{} (Space to pause, . to step, Left/Right between folds, 0-9 to seek)",
+                format.duration(player.now().min(player.total())),
+                format.duration(player.total()),
+                if player.is_paused() { ", paused" } else { "" },
+            ));
+        }
+
+        let budget = self.deadline().map(|deadline| {
+            let left = deadline.saturating_duration_since(std::time::Instant::now());
+            format!("{} left", format.duration(left))
+        });
+
+        let selected = match self.selected {
+            Some(key) => &self.programs[key],
+            None if budget.is_none() && self.start.elapsed() >= UNMATCHED_GRACE => {
+                let unmatched = self.unmatched();
+                return match unmatched.as_slice() {
+                    [] => None,
+                    [warning] => Some(format!("Warning: {}", warning)),
+                    [warning, ..] => Some(format!(
+                        "Warning: {}, and {} more",
+                        warning,
+                        unmatched.len() - 1
+                    )),
+                };
+            }
+            None => return budget,
+        };
+        let mut last_output = match selected.arrivals().last() {
+            Some(at) => format!(", last at {}", format.timestamp(self.started_at, *at)),
+            None => String::new(),
+        };
+        if selected.backlog() > 0 {
+            let backlog = format.count(selected.backlog() as u64);
+            last_output.push_str(&format!(", {} pending", backlog));
+        }
+        Some(format!(
+            "{}Selected: {} ({} lines, {}{}) (Tab for next, p to pin/unpin, P to unpin all; \
+             {} pinned; [ ] to move between folds, m to mark, d to compare; {} marked)",
+            budget.map(|budget| budget + "; ").unwrap_or_default(),
+            selected.desc(),
+            format.count(selected.arrivals().len() as u64),
+            format.bytes(selected.bytes()),
+            last_output,
+            self.pinned.len(),
+            self.marked.len(),
+        ))
+    }
+
+    /// Totals over all programs, and which of them produced the most output.
+    fn summary(&self) -> Option<String> {
+        let format = &self.display_options.format;
+        let lines: usize = self.programs.iter().map(|(_, p)| p.arrivals().len()).sum();
+        let bytes: u64 = self.programs.iter().map(|(_, p)| p.bytes()).sum();
+        let (_, top) = self.programs.iter().max_by_key(|(_, p)| p.bytes())?;
+        if bytes == 0 {
+            return None;
+        }
+
+        let secs = self.start.elapsed().as_secs_f64();
+        let mut summary = format!(
+            "Total: {} lines, {} in {}",
+            format.count(lines as u64),
+            format.bytes(bytes),
+            format.duration(self.start.elapsed())
+        );
+        if secs > 0.0 {
+            summary.push_str(&format!(" ({})", format.rate(lines as f64 / secs, "lines")));
+        }
+        if self.programs.len() > 1 {
+            summary.push_str(&format!(
+                "; most from {} ({}%)",
+                top.desc(),
+                top.bytes() * 100 / bytes
+            ));
+        }
+        if self.redactor.is_some() {
+            summary.push_str(&format!("; {}", self.redaction_count()));
+        }
+
+        Some(summary)
+    }
+
+    /// A wrong regex silently leaves the output unfolded, so point out those of the regexes
+    /// that did not match a single line. An end regex is only of interest once its start
+    /// regex matched.
+    fn unmatched(&self) -> Vec<String> {
+        if self.programs.iter().all(|(_, p)| p.arrivals().is_empty()) {
+            return vec![];
+        }
+
+        let mut warnings = vec![];
+        for pair in &self.match_pairs {
+            match pair.hits.get() {
+                (0, _) => warnings.push(format!("start regex {} never matched", pair.start)),
+                (_, 0) => warnings.push(format!(
+                    "end regex {} never matched, leaving its folds open",
+                    pair.end
+                )),
+                _ => {}
+            }
+        }
+        warnings
+    }
+
+    fn redaction_count(&self) -> String {
+        format!(
+            "{} secrets redacted",
+            self.display_options.format.count(self.redactions as u64)
+        )
+    }
+
     fn redraw(&self, draw_mode: DrawMode, stdout: &mut BufWriter<Stdout>) -> Result<()> {
         let (cx, cy) = termion::terminal_size()?;
+        let status_line = self.status_line(&draw_mode);
 
-        let cy = cy
-            - match draw_mode {
-                DrawMode::Final => self.opt.final_shrink as u16,
-                DrawMode::Ongoing => 0,
-            };
+        let shrink = match draw_mode {
+            DrawMode::Final => self.opt.final_shrink as u16,
+            DrawMode::Ongoing => 0,
+        };
+        let cy = cy.saturating_sub(shrink + status_line.is_some() as u16);
+
+        if let (DrawMode::Ongoing, [left, right]) = (&draw_mode, self.pinned.as_slice()) {
+            self.draw_split(*left, *right, cx, cy, stdout)?;
+        } else {
+            self.draw_stacked(cx, cy, stdout)?;
+        }
+
+        if let (DrawMode::Ongoing, Some(diff_view)) = (&draw_mode, &self.diff_view) {
+            self.draw_diff_view(diff_view, cx, cy, stdout)?;
+        }
+        if let (DrawMode::Ongoing, Some(scroll)) = (&draw_mode, self.help) {
+            self.draw_help(scroll, cx, cy, stdout)?;
+        }
+
+        write!(stdout, "{}", termion::clear::AfterCursor)?;
+
+        if let Some(status_line) = status_line {
+            let status_line: String = status_line.chars().take(cx as usize).collect();
+            write!(
+                stdout,
+                "{}{}{}{}{}",
+                termion::cursor::Goto(1, cy + 1),
+                termion::style::Invert,
+                status_line,
+                termion::style::Reset,
+                termion::clear::UntilNewline
+            )?;
+        }
+
+        stdout.flush()?;
+
+        Ok(())
+    }
+
+    /// With `--aggregate`, finished programs of the same shape are shown as one. Returns the
+    /// size of each group of two or more by the first program in it, and the other programs.
+    fn aggregates(&self) -> (HashMap<Key, usize>, HashSet<Key>) {
+        let mut groups: HashMap<program::Shape, Vec<Key>> = HashMap::new();
+        if self.opt.aggregate {
+            for (key, program) in &self.programs {
+                if let Some(shape) = program.shape() {
+                    groups.entry(shape).or_default().push(key);
+                }
+            }
+        }
+
+        let mut counts = HashMap::new();
+        let mut hidden = HashSet::new();
+        for keys in groups.values().filter(|keys| keys.len() > 1) {
+            counts.insert(keys[0], keys.len());
+            hidden.extend(&keys[1..]);
+        }
+
+        (counts, hidden)
+    }
+
+    fn describe<'a>(
+        &'a self,
+        key: Key,
+        program: &'a Program,
+        cx: u16,
+        allowed_extra: usize,
+        aggregated: Option<usize>,
+    ) -> display::DisplayDescription<'a> {
+        let count = match aggregated {
+            Some(count) => count,
+            None => {
+                return program.calc_display_description(
+                    cx as usize,
+                    allowed_extra,
+                    &self.display_options,
+                    &self.highlights(key),
+                )
+            }
+        };
+
+        let outcome = match program.outcome() {
+            Some(program::Outcome::Exited(0)) => "OK".to_owned(),
+            Some(outcome) => outcome.to_string(),
+            None => "running".to_owned(),
+        };
+        let line = format!("{} programs: {}, like {}", count, outcome, program.desc());
+        let mut description = display::DisplayDescription::new(cx as usize, &self.display_options);
+        description.add_line(display::DisplayLine::single(
+            0,
+            DisplayKind::ProgramTitle,
+            "",
+            line,
+        ));
+        description
+    }
+
+    /// All programs one below the other, dividing the rows between them.
+    fn draw_stacked(&self, cx: u16, cy: u16, stdout: &mut BufWriter<Stdout>) -> Result<()> {
+        let (aggregated, hidden) = self.aggregates();
+        let programs: Vec<(Key, &Program)> = self
+            .programs
+            .iter()
+            .filter(|(key, _)| !hidden.contains(key))
+            .collect();
 
         let mut descriptions = vec![];
 
-        for (_, program) in &self.programs {
-            descriptions.push(program.calc_display_description(cx as usize, 0));
+        for (key, program) in &programs {
+            descriptions.push(self.describe(*key, program, cx, 0, aggregated.get(key).copied()));
         }
 
         let mut total_lines = 0;
@@ -473,69 +2238,48 @@ impl Main {
 
         let l = descriptions.len();
         if total_lines > cy as usize {
-            for (idx, description) in descriptions.iter_mut().enumerate() {
-                let max = most_equal_divide(cy as u64, l as u64, idx as u64);
-                description.reduce_to_count(max as usize);
+            // Programs that recently printed errors get more of the screen.
+            let needs: Vec<u64> = descriptions
+                .iter()
+                .map(|description| description.lines().len() as u64)
+                .collect();
+            let weights: Vec<u64> = programs
+                .iter()
+                .map(|(_, program)| {
+                    if program.has_recent_error(self.opt.error_window) {
+                        self.opt.error_weight.max(1)
+                    } else {
+                        1
+                    }
+                })
+                .collect();
+            let counts = util::weighted_divide(cy as u64, &needs, &weights);
+            for (description, count) in descriptions.iter_mut().zip(counts) {
+                if (count as usize) < description.lines().len() {
+                    description.reduce_to_count(count as usize);
+                }
             }
         } else if total_lines < cy as usize {
             let extra = cy as usize - total_lines;
 
             descriptions.clear();
-            for (idx, (_, program)) in self.programs.iter().enumerate() {
+            for (idx, (key, program)) in programs.iter().enumerate() {
                 let added = most_equal_divide(extra as u64, l as u64, idx as u64);
-                descriptions.push(program.calc_display_description(cx as usize, added as usize));
+                let aggregated = aggregated.get(key).copied();
+                descriptions.push(self.describe(*key, program, cx, added as usize, aggregated));
             }
         }
 
         write!(stdout, "{}", termion::cursor::Goto(1, 1))?;
 
         let mut line_idx = 0;
-        for description in descriptions.iter() {
+        for ((key, _), description) in programs.iter().zip(descriptions.iter()) {
             for line in description.lines() {
-                match line.kind {
-                    DisplayKind::MiddleTextCut(true) | DisplayKind::Text(true) => {
-                        write!(
-                            stdout,
-                            "{}{}",
-                            termion::style::Bold,
-                            termion::color::Fg(termion::color::Cyan)
-                        )?;
-                    }
-                    _ => {}
-                }
-
-                write!(
-                    stdout,
-                    "{}{:>width$}{}{}",
-                    termion::style::Bold,
-                    "",
-                    line.prefix,
-                    termion::style::Reset,
-                    width = line.indent
-                )?;
-
-                match line.kind {
-                    DisplayKind::ProgramTitle | DisplayKind::Title(true) => {
-                        write!(
-                            stdout,
-                            "{}{}",
-                            termion::style::Bold,
-                            termion::color::Fg(termion::color::Cyan)
-                        )?;
-                    }
-                    _ => {}
-                }
-
-                for fragment in line.text.iter() {
-                    write!(stdout, "{}", fragment)?;
-                }
-
-                match line.kind {
-                    DisplayKind::ProgramTitle | DisplayKind::Title(true) => {
-                        write!(stdout, "{}", termion::style::Reset)?;
-                    }
-                    _ => {}
+                if line_idx == cy {
+                    // Titles alone may not fit on tiny screens.
+                    break;
                 }
+                self.write_display_line(stdout, line, self.selected == Some(*key))?;
 
                 line_idx += 1;
 
@@ -546,12 +2290,338 @@ impl Main {
                 }
             }
         }
-        write!(stdout, "{}", termion::clear::AfterCursor)?;
-        stdout.flush()?;
 
         Ok(())
     }
 
+    /// Two pinned programs next to each other, each given the full height.
+    fn draw_split(
+        &self,
+        left: Key,
+        right: Key,
+        cx: u16,
+        cy: u16,
+        stdout: &mut BufWriter<Stdout>,
+    ) -> Result<()> {
+        let half = (cx as usize).saturating_sub(1) / 2;
+        let fit = |key: Key| {
+            let program = &self.programs[key];
+            let highlights = self.highlights(key);
+            let mut description =
+                program.calc_display_description(half, 0, &self.display_options, &highlights);
+            let nr_lines = description.lines().len();
+            if nr_lines > cy as usize {
+                description.reduce_to_count(cy as usize);
+            } else if nr_lines < cy as usize {
+                description = program.calc_display_description(
+                    half,
+                    cy as usize - nr_lines,
+                    &self.display_options,
+                    &highlights,
+                );
+            }
+            description
+        };
+
+        let left_description = fit(left);
+        let right_description = fit(right);
+
+        for row in 0..cy {
+            write!(
+                stdout,
+                "{}{}",
+                termion::cursor::Goto(1, row + 1),
+                termion::clear::CurrentLine
+            )?;
+            if let Some(line) = left_description.lines().get(row as usize) {
+                self.write_display_line(stdout, line, self.selected == Some(left))?;
+            }
+
+            write!(
+                stdout,
+                "{}{}{}",
+                termion::cursor::Goto(half as u16 + 1, row + 1),
+                termion::style::Reset,
+                self.display_options.glyphs().separator,
+            )?;
+            if let Some(line) = right_description.lines().get(row as usize) {
+                self.write_display_line(stdout, line, self.selected == Some(right))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// A box over the middle of the screen, listing the differences between marked folds.
+    fn draw_diff_view(
+        &self,
+        diff_view: &DiffView,
+        cx: u16,
+        cy: u16,
+        stdout: &mut BufWriter<Stdout>,
+    ) -> Result<()> {
+        use termion::color;
+
+        let mut rows = diff_view.lines.iter().skip(diff_view.scroll);
+        self.draw_box(&diff_view.title, cx, cy, stdout, |stdout, inner| {
+            let (style, text): (String, String) = match rows.next() {
+                Some(diff::DiffLine::Hunk(s)) => (self.fg(color::Cyan), s.clone()),
+                Some(diff::DiffLine::Context(s)) => (String::new(), format!(" {}", s)),
+                Some(diff::DiffLine::Removed(s)) => (self.fg(color::Red), format!("-{}", s)),
+                Some(diff::DiffLine::Added(s)) => (self.fg(color::Green), format!("+{}", s)),
+                None => (String::new(), String::new()),
+            };
+            let text: String = text.chars().take(inner).collect();
+            write!(
+                stdout,
+                "{}{:<inner$}{}",
+                style,
+                text,
+                termion::style::Reset,
+                inner = inner
+            )?;
+            Ok(())
+        })
+    }
+
+    /// A box over the middle of the screen with the key bindings, the matchers, and the state
+    /// of each program.
+    fn draw_help(
+        &self,
+        scroll: usize,
+        cx: u16,
+        cy: u16,
+        stdout: &mut BufWriter<Stdout>,
+    ) -> Result<()> {
+        let inner = cx.saturating_sub(6) as usize;
+        let description = self.help_description(inner);
+        let mut rows = description.lines().iter().skip(scroll);
+
+        self.draw_box("Help", cx, cy, stdout, |stdout, inner| {
+            let width = match rows.next() {
+                Some(line) => {
+                    self.write_display_line(stdout, line, false)?;
+                    line.width()
+                }
+                None => 0,
+            };
+            write!(
+                stdout,
+                "{}{:width$}",
+                termion::style::Reset,
+                "",
+                width = inner.saturating_sub(width)
+            )?;
+            Ok(())
+        })
+    }
+
+    fn help_description(&self, cx: usize) -> display::DisplayDescription<'_> {
+        let mut description = display::DisplayDescription::new(cx, &self.display_options);
+        let heading =
+            |text: &'static str| DisplayLine::single(0, DisplayKind::ProgramTitle, "", text);
+        let item = |text: String| DisplayLine::single(2, DisplayKind::Text(false), "", text);
+
+        let mut keys = vec![
+            ("q", "quit"),
+            ("/", "filter lines, Esc to clear"),
+            ("Tab", "select the next program"),
+            ("[ ]", "move between the folds of the selected program"),
+            ("m", "mark the fold under the cursor, up to two"),
+            ("d", "show the differences between the marked folds"),
+            ("p", "pin the selected program, up to two side by side"),
+            ("P", "unpin all programs"),
+        ];
+        if self.player.is_some() {
+            keys.extend([
+                ("Space", "pause or resume playback"),
+                (".", "step to the next line"),
+                ("Left/Right", "move between folds"),
+                ("0-9", "seek to a tenth of the session"),
+            ]);
+        }
+        if self.is_remote() {
+            keys.push(("Ctrl+\\", "detach"));
+        }
+        keys.push(("?", "close this help"));
+
+        description.add_line(heading("Keys"));
+        for (key, action) in keys {
+            description.add_line(item(format!("{:<12}{}", key, action)));
+        }
+
+        description.add_line(heading("Matchers"));
+        for pair in &self.match_pairs {
+            description.add_line(item(format!("{}  ...  {}", pair.start, pair.end)));
+        }
+        if let Some(filter) = &self.display_options.filter {
+            description.add_line(item(format!("filter: {}", filter)));
+        }
+
+        description.add_line(heading("Programs"));
+        let format = &self.display_options.format;
+        for (_, program) in &self.programs {
+            let state = match (program.outcome(), program.current_fold()) {
+                (Some(outcome), _) => outcome.to_string(),
+                (None, _) if program.is_queued() => "queued".to_owned(),
+                (None, Some(fold)) => format!("running, in {}", fold),
+                (None, None) => "running".to_owned(),
+            };
+            let mut line = format!(
+                "{}: {}, {} lines",
+                program.desc(),
+                state,
+                format.count(program.arrivals().len() as u64)
+            );
+            if program.backlog() > 0 {
+                line.push_str(&format!(
+                    ", {} pending",
+                    format.count(program.backlog() as u64)
+                ));
+            }
+            description.add_line(item(line));
+        }
+
+        description
+    }
+
+    /// The border of a box over the middle of the screen, with the given title. Each row
+    /// inside is left to `row`, which is to fill the given amount of columns.
+    fn draw_box(
+        &self,
+        title: &str,
+        cx: u16,
+        cy: u16,
+        stdout: &mut BufWriter<Stdout>,
+        mut row: impl FnMut(&mut BufWriter<Stdout>, usize) -> Result<()>,
+    ) -> Result<()> {
+        let glyphs = self.display_options.glyphs();
+        if cx < 12 || cy < 5 {
+            return Ok(());
+        }
+
+        let (x, y) = (3, 2);
+        let width = (cx - 4) as usize;
+        let inner = width - 2;
+        let height = (cy - 2) as usize;
+
+        let title: String = format!(" {} ", title).chars().take(inner).collect();
+        write!(
+            stdout,
+            "{}{}{}{}{}{}",
+            termion::cursor::Goto(x, y),
+            termion::style::Reset,
+            glyphs.top_left,
+            title,
+            glyphs.horizontal.repeat(inner - title.chars().count()),
+            glyphs.top_right,
+        )?;
+
+        for line in 1..height as u16 - 1 {
+            write!(
+                stdout,
+                "{}{}",
+                termion::cursor::Goto(x, y + line),
+                glyphs.separator
+            )?;
+            row(stdout, inner)?;
+            write!(stdout, "{}", glyphs.separator)?;
+        }
+
+        write!(
+            stdout,
+            "{}{}{}{}",
+            termion::cursor::Goto(x, y + height as u16 - 1),
+            glyphs.bottom_left,
+            glyphs.horizontal.repeat(inner),
+            glyphs.bottom_right,
+        )?;
+
+        Ok(())
+    }
+
+    /// Escape sequence for a foreground color, unless the terminal is monochrome.
+    fn fg<C: termion::color::Color>(&self, color: C) -> String {
+        if self.profile.color {
+            termion::color::Fg(color).to_string()
+        } else {
+            String::new()
+        }
+    }
+
+    fn write_display_line(
+        &self,
+        stdout: &mut BufWriter<Stdout>,
+        line: &display::DisplayLine,
+        selected: bool,
+    ) -> Result<()> {
+        write!(stdout, "{:>width$}", "", width = line.indent)?;
+        self.write_fragment(stdout, &line.prefix)?;
+
+        for fragment in line.text.iter() {
+            match line.kind {
+                DisplayKind::ProgramTitle if selected => self.write_fragment(
+                    stdout,
+                    &display::Fragment::new(&*fragment.text, fragment.style.inverted()),
+                )?,
+                _ => self.write_fragment(stdout, fragment)?,
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write_fragment(
+        &self,
+        stdout: &mut BufWriter<Stdout>,
+        fragment: &display::Fragment,
+    ) -> Result<()> {
+        use display::Color;
+        use termion::color;
+
+        let style = fragment.style;
+        if style == display::Style::default() {
+            write!(stdout, "{}", fragment.text)?;
+            return Ok(());
+        }
+
+        if style.bold {
+            write!(stdout, "{}", termion::style::Bold)?;
+        }
+        if style.invert {
+            write!(stdout, "{}", termion::style::Invert)?;
+        }
+        if let Some(fg) = style.fg {
+            let escape = match fg {
+                Color::Cyan => self.fg(color::Cyan),
+                Color::Blue => self.fg(color::Blue),
+                Color::Green => self.fg(color::Green),
+                Color::Red => self.fg(color::Red),
+                Color::Gray => self.fg(color::LightBlack),
+            };
+            write!(stdout, "{}", escape)?;
+        }
+
+        write!(stdout, "{}{}", fragment.text, termion::style::Reset)?;
+
+        Ok(())
+    }
+
+    /// Write a line of the final dump, cleaned up as requested.
+    fn end_emit_line(&self, text: &str) {
+        let text = if self.opt.normalize_eol {
+            util::normalize_eol(text)
+        } else {
+            text
+        };
+
+        if self.opt.strip_control {
+            println!("{}", util::strip_control(text));
+        } else {
+            println!("{}", text);
+        }
+    }
+
     fn end_emit_output(&self, output: &Output, indent: usize) {
         match output {
             Output::Lines(text) => {
@@ -560,7 +2630,7 @@ impl Main {
                         print!("{:>width$}", "", width = indent);
                         println!("Line: {}", text);
                     } else {
-                        println!("{}", text);
+                        self.end_emit_line(text);
                     }
                 }
             }
@@ -570,8 +2640,12 @@ impl Main {
                     println!("StartLine: {}", encapsulation.start_line);
                     print!("{:>width$}", "", width = indent);
                     println!("StartTitle: {}", encapsulation.start_title);
+                    for (name, value) in &encapsulation.metadata {
+                        print!("{:>width$}", "", width = indent);
+                        println!("Metadata: {}={}", name, value);
+                    }
                 } else {
-                    println!("{}", encapsulation.start_line);
+                    self.end_emit_line(&encapsulation.start_line);
                 }
                 for output in &encapsulation.content {
                     self.end_emit_output(output, indent + 4);
@@ -584,7 +2658,7 @@ impl Main {
                     println!("EndTitle: {:?}", encapsulation.end_title);
                 } else {
                     if let Some(end_line) = &encapsulation.end_line {
-                        println!("{}", end_line);
+                        self.end_emit_line(end_line);
                     }
                 }
             }
@@ -593,8 +2667,15 @@ impl Main {
 
     fn end_execution(&mut self) -> Result<()> {
         for (_, program) in &self.programs {
+            if !program.originals().is_empty() && !self.opt.debug {
+                for text in program.originals() {
+                    self.end_emit_line(text);
+                }
+                continue;
+            }
+
             for output in program.content() {
-                self.end_emit_output(&output, 0);
+                self.end_emit_output(output, 0);
             }
         }
 
@@ -602,6 +2683,78 @@ impl Main {
     }
 }
 
+/// Replace the secrets in a line, if redacting, and count them.
+fn redact(redactor: &Option<redact::Redactor>, redactions: &mut usize, s: String) -> String {
+    match redactor {
+        Some(redactor) => {
+            let (s, count) = redactor.redact(s);
+            *redactions += count;
+            s
+        }
+        None => s,
+    }
+}
+
+/// The start of a line cut to at most `max` bytes, marked with the `len` it has in full.
+fn truncate(s: &str, max: usize, len: usize, format: &util::Format) -> String {
+    let mut end = max.min(s.len());
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!(
+        "{} (truncated, {} bytes)",
+        &s[..end],
+        format.count(len as u64)
+    )
+}
+
+/// Read a pipe from a child in a thread of its own, as the blocking reads of
+/// `async_std::fs::File` can stall on pipes, and the end of the output would never arrive.
+fn pipe_reader(
+    mut pipe: impl std::io::Read + Send + 'static,
+) -> futures::stream::IntoAsyncRead<Receiver<std::io::Result<Vec<u8>>>> {
+    use futures::TryStreamExt;
+
+    let (sender, receiver) = mpsc::unbounded();
+    std::thread::spawn(move || {
+        let mut chunk = vec![0; 0x10000];
+        loop {
+            let read = match pipe.read(&mut chunk) {
+                Ok(0) => return,
+                Ok(size) => Ok(chunk[..size].to_vec()),
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(err) => Err(err),
+            };
+            let failed = read.is_err();
+            if sender.unbounded_send(read).is_err() || failed {
+                return;
+            }
+        }
+    });
+
+    receiver.into_async_read()
+}
+
+/// The next program from a programs file still being read, never resolving if there is none.
+async fn next_entry(
+    entries: &mut Option<Receiver<Result<manifest::Entry>>>,
+) -> Option<Result<manifest::Entry>> {
+    use async_std::stream::StreamExt;
+
+    match entries {
+        Some(entries) => entries.next().await,
+        None => async_std::future::pending().await,
+    }
+}
+
+/// A shell-quoted description of a command, e.g. `make 'CFLAGS=-O2 -g'`.
+fn describe_argv(argv: &[String]) -> String {
+    use itertools::Itertools;
+    argv.iter()
+        .map(|s| shell_escape::escape(s.as_str().into()))
+        .join(" ")
+}
+
 /// No need for too many OS pthreads. Have the minimum that std async allows, as
 /// we are doing most processing in the main thread anyway.
 fn init_async() {
@@ -620,6 +2773,96 @@ fn init_async() {
 }
 
 fn main() -> Result<()> {
+    let opt = cmdline::Opt::from_iter_checked(std::env::args_os()).unwrap_or_else(|e| e.exit());
+
+    match &opt.command {
+        Some(cmdline::Command::SelfUpdate) => return update::self_update(),
+        Some(cmdline::Command::Wrap { command }) if !termion::is_tty(&stdout()) => {
+            // Nothing to fold for, so become the wrapped command.
+            use std::os::unix::process::CommandExt;
+            let err = std::process::Command::new(&command[0])
+                .args(&command[1..])
+                .exec();
+            return Err(err.into());
+        }
+        _ => {}
+    }
+
+    if opt.daemonize {
+        let name = opt.session.as_ref().ok_or(Error::SessionNameRequired)?;
+        remote::daemonize(name)?;
+    }
+
     init_async();
-    Main::new().run()
+
+    // Not for background sessions, where nobody would see it.
+    let check_update = opt.check_update && !opt.daemonize;
+    let result = Main::new(opt).run();
+    if check_update {
+        update::notify_newer();
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reading(max_line_length: usize) -> Reading {
+        Reading {
+            invalid_utf8: util::InvalidUtf8::Replace,
+            max_line_length,
+            format: util::Format::default(),
+        }
+    }
+
+    /// The line made of the given reads, kept up to `max` bytes.
+    fn read(chunks: &[&[u8]], max: usize) -> String {
+        let mut partial = PartialLine::default();
+        for chunk in chunks {
+            partial.extend(chunk, max);
+        }
+        partial.text(&reading(max))
+    }
+
+    #[test]
+    fn line_cap() {
+        assert_eq!(read(&[b"abc", b"def"], 0), "abcdef");
+        assert_eq!(read(&[b"abc", b"def"], 6), "abcdef");
+        assert_eq!(read(&[b"abc", b"def"], 4), "abcd (truncated, 6 bytes)");
+        assert_eq!(read(&[b"abcdef", b"gh"], 2), "ab (truncated, 8 bytes)");
+        assert_eq!(read(&[b"", b"abc"], 2), "ab (truncated, 3 bytes)");
+    }
+
+    #[test]
+    fn line_cap_with_carriage_return() {
+        assert_eq!(read(&[b"abc\r"], 0), "abc");
+        assert_eq!(read(&[b"abc", b"\r"], 3), "abc");
+        assert_eq!(read(&[b"abcd\r"], 3), "abc (truncated, 4 bytes)");
+        assert_eq!(read(&[b"ab\rcd"], 3), "ab\r (truncated, 5 bytes)");
+        assert_eq!(read(&[b"abc\r", b""], 0), "abc");
+    }
+
+    #[test]
+    fn truncating() {
+        let format = util::Format::default();
+        assert_eq!(
+            truncate("abcdef", 3, 6, &format),
+            "abc (truncated, 6 bytes)"
+        );
+        assert_eq!(
+            truncate("ab", 3, 2000, &format),
+            "ab (truncated, 2,000 bytes)"
+        );
+        // Cutting within a character leaves all of it out.
+        assert_eq!(truncate("aé", 2, 3, &format), "a (truncated, 3 bytes)");
+        assert_eq!(truncate("éé", 3, 4, &format), "é (truncated, 4 bytes)");
+        assert_eq!(truncate("日本", 1, 6, &format), " (truncated, 6 bytes)");
+        // Replacing what the cut left of a character takes more room, so it is left out too.
+        assert_eq!(
+            read(&[&[b'a', 0xe6, 0x97], b"x"], 3),
+            "a (truncated, 4 bytes)"
+        );
+    }
 }
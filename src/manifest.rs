@@ -0,0 +1,417 @@
+use super::Error;
+use anyhow::Result;
+use regex::Regex;
+use std::process::Command;
+use std::time::Duration;
+
+/// Lines of a programs file starting with this set up the command on the next regular line,
+/// one `key: value` setting per line. For the shell these are just comments.
+const DIRECTIVE: &str = "#@";
+
+/// A command to run, along with its settings.
+pub struct Entry {
+    pub command: String,
+    pub settings: Settings,
+}
+
+/// Per-program settings, given by directives preceding the command in the programs file.
+#[derive(Default, Clone, Debug)]
+pub struct Settings {
+    /// Scheduling priority, as given to `nice`.
+    pub nice: Option<i32>,
+    /// IO scheduling class and level, as given to `ionice`.
+    pub ionice: Option<IoPriority>,
+    /// Memory limit of the program's cgroup, e.g. `2G`.
+    pub memory_max: Option<String>,
+    /// CPU time limit of the program's cgroup, e.g. `50%`.
+    pub cpu_quota: Option<String>,
+    /// How long to wait before starting the program, e.g. `4s`.
+    pub delay: Option<Duration>,
+    /// A name for the program, available to templates as `{label}`.
+    pub label: Option<String>,
+    /// Extra environment variables, e.g. `CUDA_VISIBLE_DEVICES={index}`.
+    pub env: Vec<(String, String)>,
+    /// CPUs the program may run on, e.g. `0-3,8`.
+    pub cpus: Option<String>,
+    /// Responses to write to the program's stdin when its output matches, in order of priority.
+    pub expect: Vec<Expect>,
+}
+
+/// A prompt to answer, given as `expect: REGEX => RESPONSE`.
+#[derive(Clone, Debug)]
+pub struct Expect {
+    pub prompt: Regex,
+    /// Written followed by a line ending.
+    pub response: String,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct IoPriority {
+    class: i32,
+    level: i32,
+}
+
+impl IoPriority {
+    fn parse(value: &str) -> Option<Self> {
+        let (class, level) = match value.find(':') {
+            Some(pos) => (&value[..pos], value[pos + 1..].parse().ok()?),
+            None => (value, 4),
+        };
+
+        let class = match class {
+            "realtime" => 1,
+            "best-effort" => 2,
+            "idle" => 3,
+            _ => return None,
+        };
+
+        if !(0..8).contains(&level) {
+            return None;
+        }
+
+        Some(Self { class, level })
+    }
+
+    /// The value expected by the `ioprio_set` system call.
+    fn value(&self) -> i32 {
+        (self.class << 13) | self.level
+    }
+}
+
+impl Settings {
+    fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        let invalid = || Error::ManifestSetting(key.to_owned(), value.to_owned());
+
+        match key {
+            "nice" => self.nice = Some(value.parse().map_err(|_| invalid())?),
+            "ionice" => self.ionice = Some(IoPriority::parse(value).ok_or_else(invalid)?),
+            "memory" => self.memory_max = Some(value.to_owned()),
+            "cpu" => self.cpu_quota = Some(value.to_owned()),
+            "delay" => {
+                self.delay = Some(super::util::parse_duration(value).map_err(|_| invalid())?)
+            }
+            "label" => self.label = Some(value.to_owned()),
+            "env" => {
+                let pos = value.find('=').ok_or_else(invalid)?;
+                self.env
+                    .push((value[..pos].trim().to_owned(), value[pos + 1..].to_owned()));
+            }
+            "cpus" => self.cpus = Some(value.to_owned()),
+            "expect" => {
+                let pos = value.find("=>").ok_or_else(invalid)?;
+                let prompt = Regex::new(value[..pos].trim()).map_err(|_| invalid())?;
+                self.expect.push(Expect {
+                    prompt,
+                    response: value[pos + 2..].trim().to_owned(),
+                });
+            }
+            _ => return Err(Error::ManifestUnknownKey(key.to_owned()).into()),
+        }
+
+        Ok(())
+    }
+
+    /// Values for the `{index}` and `{label}` placeholders of the program with the given index.
+    /// The label defaults to the index.
+    pub fn template(&self, index: usize) -> Template {
+        let label = match &self.label {
+            Some(label) => label.replace("{index}", &index.to_string()),
+            None => index.to_string(),
+        };
+
+        Template { index, label }
+    }
+
+    /// Expand the placeholders in the settings.
+    pub fn expand(&self, template: &Template) -> Result<Settings> {
+        let settings = Settings {
+            env: self
+                .env
+                .iter()
+                .map(|(name, value)| (name.clone(), template.expand(value)))
+                .collect(),
+            cpus: self.cpus.as_deref().map(|cpus| template.expand(cpus)),
+            expect: self
+                .expect
+                .iter()
+                .map(|expect| Expect {
+                    prompt: expect.prompt.clone(),
+                    response: template.expand(&expect.response),
+                })
+                .collect(),
+            label: Some(template.label.clone()),
+            ..self.clone()
+        };
+
+        if let Some(cpus) = &settings.cpus {
+            parse_cpu_list(cpus)
+                .ok_or_else(|| Error::ManifestSetting("cpus".into(), cpus.clone()))?;
+        }
+
+        Ok(settings)
+    }
+
+    /// Build the command for running `argv` with these settings. Resource limits are applied by
+    /// running it in a transient systemd scope.
+    pub fn command(&self, argv: &[String]) -> Command {
+        let mut command = if self.memory_max.is_some() || self.cpu_quota.is_some() {
+            let mut command = Command::new("systemd-run");
+            command.args(["--user", "--scope", "--quiet", "--collect"]);
+            if let Some(memory_max) = &self.memory_max {
+                command.arg("-p").arg(format!("MemoryMax={}", memory_max));
+            }
+            if let Some(cpu_quota) = &self.cpu_quota {
+                command.arg("-p").arg(format!("CPUQuota={}", cpu_quota));
+            }
+            command.arg("--").args(argv);
+            command
+        } else {
+            let mut command = Command::new(&argv[0]);
+            command.args(&argv[1..]);
+            command
+        };
+
+        command.envs(self.env.iter().map(|(name, value)| (name, value)));
+
+        let (nice, ionice) = (self.nice, self.ionice);
+        let cpus = self.cpus.as_deref().and_then(parse_cpu_list);
+        if nice.is_some() || ionice.is_some() || cpus.is_some() {
+            use std::os::unix::process::CommandExt;
+
+            unsafe {
+                command.pre_exec(move || {
+                    if let Some(nice) = nice {
+                        if libc::setpriority(libc::PRIO_PROCESS, 0, nice) != 0 {
+                            return Err(std::io::Error::last_os_error());
+                        }
+                    }
+                    if let Some(ionice) = ionice {
+                        set_io_priority(ionice)?;
+                    }
+                    if let Some(cpus) = &cpus {
+                        set_cpu_affinity(cpus)?;
+                    }
+                    Ok(())
+                });
+            }
+        }
+
+        command
+    }
+}
+
+/// Values for the `{index}` and `{label}` placeholders of a program.
+pub struct Template {
+    index: usize,
+    label: String,
+}
+
+impl Template {
+    pub fn expand(&self, s: &str) -> String {
+        s.replace("{index}", &self.index.to_string())
+            .replace("{label}", &self.label)
+    }
+}
+
+/// CPUs beyond what a `cpu_set_t` holds cannot be set, and would panic after forking.
+#[cfg(target_os = "linux")]
+const MAX_CPUS: usize = libc::CPU_SETSIZE as usize;
+#[cfg(not(target_os = "linux"))]
+const MAX_CPUS: usize = 1024;
+
+/// Parse a list of CPUs such as `0-3,8`.
+fn parse_cpu_list(s: &str) -> Option<Vec<usize>> {
+    let mut cpus = vec![];
+
+    for part in s.split(',') {
+        let part = part.trim();
+        match part.find('-') {
+            Some(pos) => {
+                let (first, last): (usize, usize) =
+                    (part[..pos].parse().ok()?, part[pos + 1..].parse().ok()?);
+                if first > last || last >= MAX_CPUS {
+                    return None;
+                }
+                cpus.extend(first..=last);
+            }
+            None => {
+                let cpu = part.parse().ok()?;
+                if cpu >= MAX_CPUS {
+                    return None;
+                }
+                cpus.push(cpu);
+            }
+        }
+    }
+
+    Some(cpus)
+}
+
+#[cfg(target_os = "linux")]
+fn set_cpu_affinity(cpus: &[usize]) -> std::io::Result<()> {
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        for cpu in cpus {
+            libc::CPU_SET(*cpu, &mut set);
+        }
+        if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_cpu_affinity(_cpus: &[usize]) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Other,
+        "CPU affinity is only supported on Linux",
+    ))
+}
+
+#[cfg(target_os = "linux")]
+fn set_io_priority(priority: IoPriority) -> std::io::Result<()> {
+    // Who: IOPRIO_WHO_PROCESS, the calling one.
+    if unsafe { libc::syscall(libc::SYS_ioprio_set, 1, 0, priority.value()) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_io_priority(_priority: IoPriority) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Other,
+        "IO priorities are only supported on Linux",
+    ))
+}
+
+/// Parses a programs file line by line, so that programs can be started as they come.
+#[derive(Default)]
+pub struct Parser {
+    pending: Settings,
+}
+
+impl Parser {
+    /// Feed the next line, returning the entry it completes, if any.
+    pub fn push(&mut self, line: String) -> Result<Option<Entry>> {
+        if let Some(directive) = line.strip_prefix(DIRECTIVE) {
+            let directive = directive.trim();
+            if !directive.is_empty() {
+                let pos = directive
+                    .find(':')
+                    .ok_or_else(|| Error::ManifestDirective(line.clone()))?;
+                let (key, value) = (&directive[..pos], &directive[pos + 1..]);
+                self.pending.set(key.trim(), value.trim())?;
+            }
+            return Ok(None);
+        }
+
+        Ok(Some(Entry {
+            command: line,
+            settings: std::mem::take(&mut self.pending),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(lines: &[&str]) -> Result<Vec<Entry>> {
+        let mut parser = Parser::default();
+        let mut entries = vec![];
+        for line in lines {
+            entries.extend(parser.push(line.to_string())?);
+        }
+        Ok(entries)
+    }
+
+    #[test]
+    fn cpu_lists() {
+        assert_eq!(parse_cpu_list("0-3,8"), Some(vec![0, 1, 2, 3, 8]));
+        assert_eq!(parse_cpu_list(" 2 , 5-5"), Some(vec![2, 5]));
+        assert_eq!(parse_cpu_list("3-1"), None);
+        assert_eq!(parse_cpu_list("a"), None);
+        assert_eq!(parse_cpu_list("1,"), None);
+    }
+
+    #[test]
+    fn cpu_lists_beyond_cpu_set() {
+        assert_eq!(parse_cpu_list(&MAX_CPUS.to_string()), None);
+        assert_eq!(parse_cpu_list("0-4000000000"), None);
+        assert!(parse_cpu_list(&format!("0-{}", MAX_CPUS - 1)).is_some());
+    }
+
+    #[test]
+    fn directives_apply_to_the_next_command() {
+        let entries = parse(&[
+            "#@ nice: 5",
+            "#@ ionice: idle",
+            "#@ env: A = x=y",
+            "#@ expect: Proceed\\? => y",
+            "make",
+            "make test",
+        ])
+        .unwrap();
+
+        assert_eq!(entries.len(), 2);
+        let settings = &entries[0].settings;
+        assert_eq!(settings.nice, Some(5));
+        assert_eq!(settings.ionice.map(|p| p.value()), Some(3 << 13 | 4));
+        assert_eq!(settings.env, vec![("A".to_owned(), " x=y".to_owned())]);
+        assert!(settings.expect[0].prompt.is_match("Proceed? [y/N]"));
+        assert_eq!(settings.expect[0].response, "y");
+        assert_eq!(entries[1].command, "make test");
+        assert!(entries[1].settings.nice.is_none());
+    }
+
+    #[test]
+    fn bad_directives() {
+        assert!(parse(&["#@ nice"]).is_err());
+        assert!(parse(&["#@ nice: high"]).is_err());
+        assert!(parse(&["#@ ionice: urgent"]).is_err());
+        assert!(parse(&["#@ delay: soon"]).is_err());
+        assert!(parse(&["#@ colour: red"]).is_err());
+        assert!(parse(&["#@ expect: ( => y"]).is_err());
+    }
+
+    #[test]
+    fn expect_rules() {
+        let entries = parse(&[
+            "#@ expect: ^Continue => yes => really",
+            "#@ expect:  Password:\\s*$=>{label}-pass ",
+            "deploy",
+        ])
+        .unwrap();
+
+        let expect = &entries[0].settings.expect;
+        assert_eq!(expect.len(), 2);
+        assert!(expect[0].prompt.is_match("Continue?"));
+        assert!(!expect[0].prompt.is_match("Do not Continue"));
+        assert_eq!(expect[0].response, "yes => really");
+        assert!(expect[1].prompt.is_match("Password: "));
+        assert_eq!(expect[1].response, "{label}-pass");
+
+        let settings = &entries[0].settings;
+        let expanded = settings.expand(&settings.template(3)).unwrap();
+        assert_eq!(expanded.expect[1].response, "3-pass");
+
+        assert!(parse(&["#@ expect: Proceed?"]).is_err());
+    }
+
+    #[test]
+    fn templates() {
+        let mut settings = Settings::default();
+        settings.set("label", "gpu{index}").unwrap();
+        settings.set("env", "CUDA_VISIBLE_DEVICES={index}").unwrap();
+        settings.set("cpus", "{index}").unwrap();
+
+        let template = settings.template(2);
+        let expanded = settings.expand(&template).unwrap();
+        assert_eq!(expanded.label.as_deref(), Some("gpu2"));
+        assert_eq!(expanded.env[0].1, "2");
+        assert_eq!(expanded.cpus.as_deref(), Some("2"));
+        assert_eq!(template.expand("{label}:{index}"), "gpu2:2");
+    }
+}
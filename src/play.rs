@@ -0,0 +1,302 @@
+use super::program::Outcome;
+use super::session::SessionProgram;
+use std::ops::Range;
+use std::time::{Duration, Instant};
+
+/// A line of a saved session, to be shown at the time at which it originally arrived.
+struct Event {
+    program: usize,
+    at: Duration,
+    seq: u64,
+    text: String,
+}
+
+/// Where playback went, in terms of the events to show.
+pub enum Seek {
+    /// Show these events in addition to those shown already.
+    Forward(Range<usize>),
+    /// Start over from empty programs, and show these events.
+    Rewind(Range<usize>),
+}
+
+/// Plays a saved session back in its original pace, with controls for pausing, stepping
+/// and seeking. Events are referred to by their index in time order.
+pub struct Player {
+    events: Vec<Event>,
+    /// Events that start or end folds, in ascending order.
+    boundaries: Vec<usize>,
+    outcomes: Vec<Option<Outcome>>,
+    /// Amount of events shown so far.
+    position: usize,
+    /// Time into the session at which playback was last paused, resumed or moved.
+    clock: Duration,
+    /// When playback was resumed, unless paused.
+    resumed: Option<Instant>,
+}
+
+impl Player {
+    /// Take the lines out of the given programs, which are to start out empty.
+    pub fn new(programs: &mut [SessionProgram]) -> Self {
+        let mut events = vec![];
+        let mut outcomes = vec![];
+
+        for (program, saved) in programs.iter_mut().enumerate() {
+            for line in std::mem::take(&mut saved.lines) {
+                events.push(Event {
+                    program,
+                    at: Duration::from_millis(line.time),
+                    seq: line.seq,
+                    text: line.text,
+                });
+            }
+            outcomes.push(saved.outcome);
+        }
+        // Stable, so that lines of the same program arriving together keep their order even
+        // where sessions did not number them.
+        events.sort_by_key(|event| (event.at, event.seq));
+
+        Player {
+            events,
+            boundaries: vec![],
+            outcomes,
+            position: 0,
+            clock: Duration::from_secs(0),
+            resumed: Some(Instant::now()),
+        }
+    }
+
+    pub fn mark_boundaries(&mut self, is_boundary: impl Fn(&str) -> bool) {
+        self.boundaries = (0..self.events.len())
+            .filter(|idx| is_boundary(&self.events[*idx].text))
+            .collect();
+    }
+
+    /// The program, arrival time and text of an event.
+    pub fn event(&self, idx: usize) -> (usize, Duration, String) {
+        let event = &self.events[idx];
+        (event.program, event.at, event.text.clone())
+    }
+
+    /// How programs ended, to be shown once playback reaches the end.
+    pub fn outcomes(&self) -> &[Option<Outcome>] {
+        &self.outcomes
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.position == self.events.len()
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.resumed.is_none()
+    }
+
+    /// Time into the session being shown.
+    pub fn now(&self) -> Duration {
+        match self.resumed {
+            Some(resumed) => self.clock + resumed.elapsed(),
+            None => self.clock,
+        }
+    }
+
+    pub fn total(&self) -> Duration {
+        self.events
+            .last()
+            .map_or(Duration::from_secs(0), |event| event.at)
+    }
+
+    /// When the next event is due, unless paused.
+    pub fn next_due(&self) -> Option<Instant> {
+        let resumed = self.resumed?;
+        let event = self.events.get(self.position)?;
+        Some(resumed + event.at.saturating_sub(self.clock))
+    }
+
+    /// The events that became due, which are now considered shown.
+    pub fn take_due(&mut self) -> Range<usize> {
+        let now = self.now();
+        let start = self.position;
+        while self.position < self.events.len() && self.events[self.position].at <= now {
+            self.position += 1;
+        }
+        start..self.position
+    }
+
+    pub fn toggle_pause(&mut self) {
+        self.clock = self.now();
+        self.resumed = match self.resumed {
+            Some(_) => None,
+            None => Some(Instant::now()),
+        };
+    }
+
+    /// Pause, and show a single more event.
+    pub fn step(&mut self) -> Seek {
+        self.resumed = None;
+        self.seek((self.position + 1).min(self.events.len()))
+    }
+
+    /// Up to and including the next event that starts or ends a fold.
+    pub fn next_boundary(&mut self) -> Seek {
+        let next = self.boundaries.partition_point(|idx| *idx < self.position);
+        let position = self
+            .boundaries
+            .get(next)
+            .map_or(self.events.len(), |idx| idx + 1);
+        self.seek(position)
+    }
+
+    /// Back to just after the previous event that starts or ends a fold.
+    pub fn previous_boundary(&mut self) -> Seek {
+        let previous = self
+            .boundaries
+            .partition_point(|idx| idx + 1 < self.position);
+        let position = match previous {
+            0 => 0,
+            previous => self.boundaries[previous - 1] + 1,
+        };
+        self.seek(position)
+    }
+
+    /// To the given tenth of the session's duration.
+    pub fn seek_tenth(&mut self, tenth: u32) -> Seek {
+        let at = self.total() * tenth / 10;
+        let position = self.events.partition_point(|event| event.at < at);
+        self.seek(position)
+    }
+
+    fn seek(&mut self, position: usize) -> Seek {
+        let previous = self.position;
+        self.position = position;
+        self.clock = match position {
+            0 => Duration::from_secs(0),
+            position => self.events[position - 1].at,
+        };
+        if self.resumed.is_some() {
+            self.resumed = Some(Instant::now());
+        }
+
+        if position >= previous {
+            Seek::Forward(previous..position)
+        } else {
+            Seek::Rewind(0..position)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::SessionLine;
+
+    fn player() -> Player {
+        let program = |lines: &[(u64, u64, &str)], outcome| SessionProgram {
+            desc: String::new(),
+            label: None,
+            outcome,
+            lines: lines
+                .iter()
+                .map(|(time, seq, text)| SessionLine {
+                    time: *time,
+                    text: text.to_string(),
+                    source: 0,
+                    number: 0,
+                    seq: *seq,
+                })
+                .collect(),
+            folds: vec![],
+        };
+        let mut programs = [
+            program(
+                &[(0, 1, "start a"), (10, 3, "x"), (20, 4, "end a")],
+                Some(Outcome::Exited(0)),
+            ),
+            program(&[(0, 2, "y"), (30, 5, "start b")], None),
+        ];
+
+        let mut player = Player::new(&mut programs);
+        assert!(programs.iter().all(|program| program.lines.is_empty()));
+        player.mark_boundaries(|text| text.starts_with("start") || text.starts_with("end"));
+        player
+    }
+
+    /// Whether playback starts over, and the events it then shows.
+    fn seek(seek: Seek) -> (bool, Range<usize>) {
+        match seek {
+            Seek::Forward(events) => (false, events),
+            Seek::Rewind(events) => (true, events),
+        }
+    }
+
+    #[test]
+    fn events_in_time_order() {
+        let player = player();
+        let events: Vec<_> = (0..5).map(|idx| player.event(idx)).collect();
+        let ms = Duration::from_millis;
+        assert_eq!(
+            events,
+            [
+                (0, ms(0), "start a".to_owned()),
+                (1, ms(0), "y".to_owned()),
+                (0, ms(10), "x".to_owned()),
+                (0, ms(20), "end a".to_owned()),
+                (1, ms(30), "start b".to_owned()),
+            ]
+        );
+        assert_eq!(player.total(), ms(30));
+        assert!(matches!(
+            player.outcomes(),
+            [Some(Outcome::Exited(0)), None]
+        ));
+    }
+
+    #[test]
+    fn stepping() {
+        let mut player = player();
+        assert_eq!(seek(player.step()), (false, 0..1));
+        assert!(player.is_paused());
+        assert_eq!(player.next_due(), None);
+        assert_eq!(seek(player.step()), (false, 1..2));
+        assert_eq!(player.now(), Duration::from_millis(0));
+        assert_eq!(seek(player.step()), (false, 2..3));
+        assert_eq!(player.now(), Duration::from_millis(10));
+
+        for _ in 0..3 {
+            player.step();
+        }
+        assert!(player.is_finished());
+        assert_eq!(seek(player.step()), (false, 5..5));
+    }
+
+    #[test]
+    fn boundaries() {
+        let mut player = player();
+        player.step();
+        player.step();
+        assert_eq!(seek(player.next_boundary()), (false, 2..4));
+        assert_eq!(seek(player.next_boundary()), (false, 4..5));
+        assert_eq!(seek(player.next_boundary()), (false, 5..5));
+
+        assert_eq!(seek(player.previous_boundary()), (true, 0..4));
+        assert_eq!(seek(player.previous_boundary()), (true, 0..1));
+        assert_eq!(seek(player.previous_boundary()), (true, 0..0));
+        assert_eq!(player.now(), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn seeking() {
+        let mut player = player();
+        player.toggle_pause();
+        assert_eq!(seek(player.seek_tenth(5)), (false, 0..3));
+        assert_eq!(player.now(), Duration::from_millis(10));
+        assert_eq!(seek(player.seek_tenth(9)), (false, 3..4));
+        assert_eq!(seek(player.seek_tenth(0)), (true, 0..0));
+        assert!(player.is_paused());
+
+        // Once resumed, playback goes on from there.
+        player.toggle_pause();
+        let due = player.take_due();
+        assert_eq!(due.start, 0);
+        assert!(due.end >= 2);
+        assert!(player.next_due().is_some());
+    }
+}
@@ -0,0 +1,73 @@
+use std::str::FromStr;
+
+/// What the terminal is capable of rendering.
+#[derive(Clone, Copy, Debug)]
+pub struct Profile {
+    /// The basic ANSI colors are available. Otherwise only bold and reverse video are used.
+    pub color: bool,
+    /// Box drawing and other non-ASCII glyphs can be shown.
+    pub unicode: bool,
+}
+
+impl FromStr for Profile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (color, unicode) = match s {
+            "auto" => return Ok(detect()),
+            "color" => (true, true),
+            "color-ascii" => (true, false),
+            "mono" => (false, true),
+            "ascii" => (false, false),
+            _ => {
+                return Err(format!(
+                    "unknown profile {}, expected one of: auto, color, color-ascii, mono, ascii",
+                    s
+                ))
+            }
+        };
+
+        Ok(Profile { color, unicode })
+    }
+}
+
+/// Guess the profile from the environment and the terminfo entry of the terminal.
+pub fn detect() -> Profile {
+    let term = std::env::var("TERM").unwrap_or_default();
+    if term.is_empty() || term == "dumb" {
+        return Profile {
+            color: false,
+            unicode: false,
+        };
+    }
+
+    let color = std::env::var_os("NO_COLOR").is_none() && terminfo_colors().is_none_or(|n| n >= 8);
+
+    // Consoles and serial terminals are unlikely to have fonts with box drawing glyphs.
+    let unicode = locale_is_utf8() && term != "linux" && !term.starts_with("vt");
+
+    Profile { color, unicode }
+}
+
+fn terminfo_colors() -> Option<i32> {
+    let output = std::process::Command::new("tput")
+        .arg("colors")
+        .stderr(std::process::Stdio::null())
+        .output()
+        .ok()?;
+
+    String::from_utf8_lossy(&output.stdout).trim().parse().ok()
+}
+
+fn locale_is_utf8() -> bool {
+    for var in &["LC_ALL", "LC_CTYPE", "LANG"] {
+        if let Ok(value) = std::env::var(var) {
+            if !value.is_empty() {
+                let value = value.to_lowercase();
+                return value.contains("utf-8") || value.contains("utf8");
+            }
+        }
+    }
+
+    false
+}
@@ -1,14 +1,111 @@
-use super::display::{DisplayDescription, DisplayKind, DisplayLine};
+use super::display::{
+    Color, DisplayDescription, DisplayKind, DisplayLine, DisplayOptions, Fragment, Style,
+};
+use super::index::SearchIndex;
+use super::manifest::Expect;
 use super::{Encapsulation, Matchers, Output, PairId, Text};
 use futures::SinkExt;
+use serde::{Deserialize, Serialize};
 use smallvec::SmallVec;
-use std::process::Child;
+use std::process::{Child, ChildStdin, ExitStatus};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 pub struct Program {
     desc: String,
+    /// Given with the `label` setting of the programs file.
+    label: Option<String>,
     content: Vec<Output>,
+    /// Arrival time of each line, relative to the start of the session. These are in the same
+    /// order as the lines given by `for_each_line`.
+    arrivals: Vec<Duration>,
+    /// Position of each line among the lines of all programs in order of arrival, from 1.
+    sequence: Vec<u64>,
+    /// Lines as they arrived, where `--rewrite` changed what is kept in `content`. Empty
+    /// without rewrite rules.
+    originals: Vec<Text>,
+    /// Total size of the lines, not counting line endings.
+    bytes: u64,
+    outcome: Option<Outcome>,
+    /// Kept when filtering needs to be fast on large amounts of output.
+    index: Option<SearchIndex>,
     pub child: Option<Child>,
+    /// When a program that is queued for starting is due.
+    starts_at: Option<Instant>,
+    /// When the line rate was last sampled, and the amount of lines at that time.
+    rate_sample: (Instant, usize),
+    /// Lines per second between the last two samples.
+    rate: f64,
+    /// When a line matching `--error-regex` last arrived.
+    last_error: Option<Instant>,
+    /// Prompts to answer, from the `expect` settings of the programs file.
+    expect: Vec<Expect>,
+    /// The readers whose incomplete line was answered already.
+    answered: Vec<usize>,
+    /// Where responses go on their way to the stdin of the child.
+    responses: Option<std::sync::mpsc::Sender<String>>,
     shutdowns: Vec<super::Sender<()>>,
+    /// Shown when the broker falls behind on this program.
+    backlog: Arc<AtomicUsize>,
+}
+
+/// What programs are compared by for `--aggregate`: how they ended, and the kind, nesting
+/// depth and title of each of their folds, in order.
+#[derive(Debug, PartialEq, Eq, Hash)]
+pub struct Shape {
+    outcome: String,
+    folds: Vec<(usize, PairId, Text)>,
+}
+
+/// How a program's child process ended.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum Outcome {
+    Exited(i32),
+    Signaled(i32),
+}
+
+impl std::fmt::Display for Outcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Outcome::Exited(code) => write!(f, "exited with {}", code),
+            Outcome::Signaled(signal) => write!(f, "killed by signal {}", signal),
+        }
+    }
+}
+
+impl Outcome {
+    /// End our own process in the same way.
+    pub fn exit(self) -> ! {
+        match self {
+            Outcome::Exited(code) => std::process::exit(code),
+            Outcome::Signaled(signal) => {
+                unsafe {
+                    libc::signal(signal, libc::SIG_DFL);
+                    libc::raise(signal);
+                }
+                // For signals that do not terminate by default.
+                std::process::exit(128 + signal)
+            }
+        }
+    }
+}
+
+impl From<ExitStatus> for Outcome {
+    fn from(status: ExitStatus) -> Self {
+        use std::os::unix::process::ExitStatusExt;
+
+        match status.code() {
+            Some(code) => Outcome::Exited(code),
+            None => Outcome::Signaled(status.signal().unwrap_or(0)),
+        }
+    }
+}
+
+/// A fold that a line started or ended, by its title.
+pub enum FoldChange {
+    Started(String),
+    Ended(String),
 }
 
 enum OutputPush {
@@ -21,41 +118,340 @@ impl Program {
         &self.content
     }
 
+    pub fn desc(&self) -> &str {
+        &self.desc
+    }
+
+    pub fn arrivals(&self) -> &[Duration] {
+        &self.arrivals
+    }
+
+    pub fn sequence(&self) -> &[u64] {
+        &self.sequence
+    }
+
+    pub fn originals(&self) -> &[Text] {
+        &self.originals
+    }
+
+    /// Keep the line as it arrived, before it gets rewritten and appended.
+    pub fn keep_original(&mut self, s: &str) {
+        self.originals.push(s.to_owned());
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    pub fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+
+    pub fn with_label(self, label: Option<String>) -> Self {
+        Self { label, ..self }
+    }
+
+    pub fn with_expect(self, expect: Vec<Expect>) -> Self {
+        Self { expect, ..self }
+    }
+
+    pub fn with_backlog(self, backlog: Arc<AtomicUsize>) -> Self {
+        Self { backlog, ..self }
+    }
+
+    pub fn backlog(&self) -> usize {
+        self.backlog.load(Ordering::Relaxed)
+    }
+
+    /// Write the response of the first `expect` rule matching the output to the child's stdin,
+    /// returning whether there was one.
+    pub fn respond(&mut self, s: &str) -> bool {
+        let expect = match self.expect.iter().find(|expect| expect.prompt.is_match(s)) {
+            Some(expect) => expect,
+            None => return false,
+        };
+        if let Some(responses) = &self.responses {
+            let _ = responses.send(expect.response.clone());
+        }
+        true
+    }
+
+    /// Like `respond`, for a line that `reader` sent while incomplete. Such a line is answered
+    /// at most once, and then not again when it completes.
+    pub fn respond_prompt(&mut self, reader: usize, s: &str, complete: bool) {
+        let answered = self.answered.contains(&reader);
+        if complete {
+            self.answered.retain(|other| *other != reader);
+        }
+        if !answered && self.respond(s) && !complete {
+            self.answered.push(reader);
+        }
+    }
+
+    pub fn outcome(&self) -> Option<Outcome> {
+        self.outcome
+    }
+
+    pub fn set_outcome(&mut self, outcome: Option<Outcome>) {
+        self.outcome = outcome;
+    }
+
     pub fn new(desc: String, shutdowns: Vec<super::Sender<()>>) -> Self {
         Self {
             desc,
+            label: None,
             child: None,
             content: vec![],
+            arrivals: vec![],
+            sequence: vec![],
+            originals: vec![],
+            bytes: 0,
+            outcome: None,
+            index: None,
+            starts_at: None,
+            rate_sample: (Instant::now(), 0),
+            rate: 0.0,
+            last_error: None,
+            expect: vec![],
+            answered: vec![],
+            responses: None,
             shutdowns,
+            backlog: Default::default(),
         }
     }
 
+    /// Send a signal to the process group of the child, or only to the child if it does not
+    /// lead a group of its own.
+    pub fn signal(&self, signal: i32) {
+        // Once reaped, the pid may have been given to another process already.
+        if self.outcome.is_some() {
+            return;
+        }
+        if let Some(child) = &self.child {
+            unsafe {
+                let no_group = libc::kill(-(child.id() as i32), signal) != 0
+                    && std::io::Error::last_os_error().raw_os_error() == Some(libc::ESRCH);
+                if no_group {
+                    libc::kill(child.id() as i32, signal);
+                }
+            }
+        }
+    }
+
+    /// Whether the child is in the foreground process group of our terminal, which then sends
+    /// it the signals of keys such as Ctrl+C by itself.
+    pub fn in_terminal_foreground(&self) -> bool {
+        let child = match &self.child {
+            Some(child) => child,
+            None => return false,
+        };
+
+        unsafe {
+            let group = libc::getpgid(child.id() as i32);
+            let terminal = [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO]
+                .iter()
+                .map(|fd| libc::tcgetpgrp(*fd))
+                .find(|pgrp| *pgrp >= 0);
+            group >= 0 && terminal == Some(group)
+        }
+    }
+
+    /// Wait for the child to terminate and record how it went. Unless `block` is set, a child
+    /// that is still running is left alone and no outcome is recorded.
+    pub fn collect_outcome(&mut self, block: bool) {
+        if let Some(child) = &mut self.child {
+            let status = if block {
+                child.wait().ok()
+            } else {
+                child.try_wait().ok().flatten()
+            };
+            if let Some(status) = status {
+                self.outcome = Some(status.into());
+            }
+        }
+    }
+
+    /// Visit all lines in the order in which they arrived, including those of fold boundaries.
+    pub(crate) fn for_each_line<'a>(&'a self, mut f: impl FnMut(&'a Text)) {
+        visit_lines(&self.content, &mut f);
+    }
+
+    /// Paths to all folds, in the order in which they started. A path is the index of the fold
+    /// in each level of content leading to it.
+    pub(crate) fn fold_paths(&self) -> Vec<Vec<usize>> {
+        fn collect(content: &[Output], path: &mut Vec<usize>, paths: &mut Vec<Vec<usize>>) {
+            for (idx, output) in content.iter().enumerate() {
+                if let Output::Encapsulation(encapsulation) = output {
+                    path.push(idx);
+                    paths.push(path.clone());
+                    collect(&encapsulation.content, path, paths);
+                    path.pop();
+                }
+            }
+        }
+
+        let mut paths = vec![];
+        collect(&self.content, &mut vec![], &mut paths);
+        paths
+    }
+
+    /// The shape of a program that ended, for aggregating it with others.
+    pub fn shape(&self) -> Option<Shape> {
+        let outcome = self.outcome?.to_string();
+        let folds = self
+            .fold_paths()
+            .into_iter()
+            .filter_map(|path| {
+                let fold = self.fold(&path)?;
+                Some((path.len(), fold.pair_id, fold.start_title.clone()))
+            })
+            .collect();
+
+        Some(Shape { outcome, folds })
+    }
+
+    /// Title of the innermost fold that did not end yet.
+    pub fn current_fold(&self) -> Option<&str> {
+        let mut content = &self.content;
+        let mut found = None;
+
+        while let Some(Output::Encapsulation(encapsulation)) = content.last() {
+            if encapsulation.is_ended() {
+                break;
+            }
+            found = Some(encapsulation.start_title.as_str());
+            content = &encapsulation.content;
+        }
+
+        found
+    }
+
+    /// Numbers of the lines starting and ending each fold, in arrival order from 0, in the
+    /// order of `fold_paths`.
+    pub(crate) fn fold_spans(&self) -> Vec<(usize, Option<usize>)> {
+        fn collect(content: &[Output], nr: &mut usize, spans: &mut Vec<(usize, Option<usize>)>) {
+            for output in content {
+                match output {
+                    Output::Lines(lines) => *nr += lines.len(),
+                    Output::Encapsulation(encapsulation) => {
+                        let idx = spans.len();
+                        spans.push((*nr, None));
+                        *nr += 1;
+                        collect(&encapsulation.content, nr, spans);
+                        if encapsulation.end_line.is_some() {
+                            spans[idx].1 = Some(*nr);
+                            *nr += 1;
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut spans = vec![];
+        collect(&self.content, &mut 0, &mut spans);
+        spans
+    }
+
+    pub(crate) fn fold(&self, path: &[usize]) -> Option<&Encapsulation> {
+        let mut content = &self.content;
+        let mut found = None;
+
+        for idx in path {
+            match content.get(*idx) {
+                Some(Output::Encapsulation(encapsulation)) => {
+                    content = &encapsulation.content;
+                    found = Some(encapsulation);
+                }
+                _ => return None,
+            }
+        }
+
+        found
+    }
+
     pub async fn shutdown(&mut self) {
         for mut shutdown in self.shutdowns.drain(..) {
             let _ = shutdown.send(()).await;
         }
     }
 
-    pub fn with_child(self, child: Child) -> Self {
+    /// The child process of a program that was queued has started.
+    pub fn start(
+        &mut self,
+        mut child: Child,
+        shutdowns: Vec<super::Sender<()>>,
+        backlog: Arc<AtomicUsize>,
+    ) {
+        if let Some(stdin) = child.stdin.take() {
+            self.responses = Some(write_lines(stdin));
+        }
+        self.child = Some(child);
+        self.shutdowns = shutdowns;
+        self.backlog = backlog;
+        self.starts_at = None;
+    }
+
+    /// Update the line rate from the lines that arrived since the previous sample.
+    pub fn sample_rate(&mut self, now: Instant) {
+        let (at, lines) = self.rate_sample;
+        let secs = now.saturating_duration_since(at).as_secs_f64();
+        if secs > 0.0 {
+            self.rate = (self.arrivals.len() - lines) as f64 / secs;
+        }
+        self.rate_sample = (now, self.arrivals.len());
+    }
+
+    pub fn note_error(&mut self) {
+        self.last_error = Some(Instant::now());
+    }
+
+    /// Whether an error arrived within the given time.
+    pub fn has_recent_error(&self, window: Duration) -> bool {
+        matches!(self.last_error, Some(at) if at.elapsed() < window)
+    }
+
+    /// Whether the program waits for its turn to start.
+    pub fn is_queued(&self) -> bool {
+        self.starts_at.is_some()
+    }
+
+    pub fn set_starts_at(&mut self, starts_at: Option<Instant>) {
+        self.starts_at = starts_at;
+    }
+
+    /// Keep a search index of the lines from now on, if enabled.
+    pub fn with_index(self, enabled: bool) -> Self {
         Self {
-            child: Some(child),
+            index: if enabled {
+                Some(SearchIndex::default())
+            } else {
+                None
+            },
             ..self
         }
     }
 
-    pub(crate) fn append_line(&mut self, s: Text, matchers: &Matchers<'_>) {
+    pub(crate) fn append_line(
+        &mut self,
+        s: Text,
+        at: Duration,
+        matchers: &Matchers<'_>,
+    ) -> Option<FoldChange> {
         enum Side {
             Start,
             End,
-        };
+        }
         let mut encapsulation = None;
         if matchers.regex_set.is_match(&s) {
             for (pair_id, pair) in matchers.match_pairs.iter().enumerate() {
+                let (starts, ends) = pair.hits.get();
                 if let Some(captures) = pair.start.captures(&s) {
+                    pair.hits.set((starts + 1, ends));
                     encapsulation = Some((pair_id, Side::Start, captures));
                     break;
                 }
                 if let Some(captures) = pair.end.captures(&s) {
+                    pair.hits.set((starts, ends + 1));
                     encapsulation = Some((pair_id, Side::End, captures));
                     break;
                 }
@@ -73,6 +469,18 @@ impl Program {
             };
             match side {
                 Side::Start => {
+                    let regex = &matchers.match_pairs[pair_id].start;
+                    let metadata = regex
+                        .capture_names()
+                        .flatten()
+                        .filter(|name| *name != "M")
+                        .filter_map(|name| {
+                            let value = captures.name(name)?;
+                            Some((name.to_owned(), value.as_str().to_owned()))
+                        })
+                        .collect();
+                    self.arrived(&s, at, matchers);
+                    let change = FoldChange::Started(title.clone());
                     let encapsulation = Encapsulation {
                         start_title: title,
                         pair_id,
@@ -80,20 +488,48 @@ impl Program {
                         end_line: None,
                         end_title: None,
                         content: vec![],
+                        nr_lines: 0,
+                        hash: None,
+                        metadata,
                     };
                     Self::push_regular(&mut self.content, OutputPush::Encapsulation(encapsulation));
+                    Some(change)
                 }
                 Side::End => {
-                    let _ = Self::push_end(&mut self.content, (title, s, pair_id));
+                    let ending = self.current_fold().unwrap_or_default().to_owned();
+                    let line = s.clone();
+                    match Self::push_end(&mut self.content, (title, s, pair_id)) {
+                        // Nothing to end, so the line is dropped.
+                        Some(_) => None,
+                        None => {
+                            self.arrived(&line, at, matchers);
+                            Some(FoldChange::Ended(ending))
+                        }
+                    }
                 }
             }
         } else {
+            self.arrived(&s, at, matchers);
             Self::push_regular(&mut self.content, OutputPush::Line(s));
+            None
+        }
+    }
+
+    /// Account for a line that is kept, keeping `arrivals` and the index in step with the
+    /// lines in `content`.
+    fn arrived(&mut self, s: &str, at: Duration, matchers: &Matchers<'_>) {
+        let seq = matchers.sequence.get() + 1;
+        matchers.sequence.set(seq);
+        self.arrivals.push(at);
+        self.sequence.push(seq);
+        self.bytes += s.len() as u64;
+        if let Some(index) = &mut self.index {
+            index.push(s);
         }
     }
 
     fn push_end(
-        content: &mut Vec<Output>,
+        content: &mut [Output],
         s: (String, String, PairId),
     ) -> Option<(String, String, PairId)> {
         if let Some(last) = content.last_mut() {
@@ -101,11 +537,14 @@ impl Program {
                 Output::Lines(_) => Some(s),
                 Output::Encapsulation(encapsulation) => {
                     if encapsulation.is_ended() {
-                        return Some(s);
+                        Some(s)
                     } else {
                         if let Some((title, s, _)) = Self::push_end(&mut encapsulation.content, s) {
                             encapsulation.end_line = Some(s);
                             encapsulation.end_title = Some(title);
+                            encapsulation.hash = Some(fold_hash(encapsulation));
+                        } else {
+                            encapsulation.nr_lines += 1;
                         }
                         None
                     }
@@ -138,6 +577,7 @@ impl Program {
                             }
                         }
                     } else {
+                        encapsulation.nr_lines += 1;
                         Self::push_regular(&mut encapsulation.content, s);
                     }
                 }
@@ -158,18 +598,441 @@ impl Program {
         &'a self,
         cx: usize,
         allowed_extra: usize,
+        options: &'a DisplayOptions,
+        highlights: &[&[usize]],
     ) -> DisplayDescription<'a> {
-        let mut dd = DisplayDescription::new(cx);
+        let mut dd = DisplayDescription::new(cx, options);
 
-        dd.add_line(DisplayLine {
-            indent: 0,
-            kind: DisplayKind::ProgramTitle,
-            prefix: "",
-            text: SmallVec::from(&[self.desc.as_str().into()][..]),
-        });
+        let mut title = DisplayLine::single(0, DisplayKind::ProgramTitle, "", self.desc.as_str());
+        if let Some(outcome) = &self.outcome {
+            title.text.push(Fragment::new(
+                format!(" ({})", outcome),
+                DisplayKind::ProgramTitle.text_style(),
+            ));
+        } else if let Some(starts_at) = self.starts_at {
+            let wait = starts_at.saturating_duration_since(Instant::now());
+            title.text.push(Fragment::new(
+                format!(" (queued, starts in {})", options.format.duration(wait)),
+                DisplayKind::ProgramTitle.text_style(),
+            ));
+        }
+
+        if !self.arrivals.is_empty() {
+            let format = &options.format;
+            let mut stats = format!(
+                " [{} lines, {}",
+                format.count(self.arrivals.len() as u64),
+                format.bytes(self.bytes)
+            );
+            if self.rate > 0.0 {
+                stats.push_str(&format!(", {}", format.rate(self.rate, "lines")));
+            }
+            stats.push(']');
+            title
+                .text
+                .push(Fragment::new(stats, DisplayKind::ProgramTitle.text_style()));
+        }
+
+        dd.add_line(title);
+
+        if options.timeline {
+            if let Some(timeline) = self.timeline(cx, options) {
+                dd.add_line(timeline);
+            }
+        }
+
+        for path in highlights {
+            if let Some(encapsulation) = self.fold(path) {
+                dd.highlight(encapsulation);
+            }
+        }
+
+        if let (Some(index), Some(filter)) = (&self.index, &options.filter) {
+            dd.set_matches(index.search(filter, |f| self.for_each_line(|line| f(line))));
+        }
 
         dd.add_content(&self.content, 0, allowed_extra, true);
 
         dd
     }
 }
+
+/// What a program was doing at some point of its timeline.
+#[derive(Clone, Copy, PartialEq)]
+enum Phase {
+    Idle,
+    Running,
+    Done,
+    Failed,
+}
+
+impl Phase {
+    fn color(self) -> Color {
+        match self {
+            Phase::Idle => Color::Gray,
+            Phase::Running => Color::Blue,
+            Phase::Done => Color::Green,
+            Phase::Failed => Color::Red,
+        }
+    }
+}
+
+impl Program {
+    /// A line spanning from the first output of the program to the last, on which each
+    /// top-level fold takes columns in proportion to its duration.
+    fn timeline<'a>(&self, cx: usize, options: &DisplayOptions) -> Option<DisplayLine<'a>> {
+        // Room for the ellipsis, so that the line is never cut.
+        let width = cx.saturating_sub(4);
+        if width == 0 {
+            return None;
+        }
+        let glyph = |phase: Phase| options.glyphs().phases[phase as usize];
+        let fragment = |phase: Phase, columns: usize| {
+            let style = Style {
+                fg: Some(phase.color()),
+                ..Style::default()
+            };
+            Fragment::new(glyph(phase).repeat(columns), style)
+        };
+        let line = |text| DisplayLine {
+            indent: 0,
+            kind: DisplayKind::Text(false),
+            prefix: Fragment::new("", Style::default()),
+            text,
+        };
+
+        let (first, last) = match (self.arrivals.first(), self.arrivals.last()) {
+            (Some(first), Some(last)) => (*first, *last),
+            _ if self.starts_at.is_some() => {
+                return Some(line(SmallVec::from_elem(fragment(Phase::Idle, width), 1)));
+            }
+            _ => return None,
+        };
+
+        let mut phases: Vec<(Duration, Duration, Phase)> = self
+            .fold_paths()
+            .iter()
+            .zip(self.fold_spans())
+            .filter(|(path, _)| path.len() == 1)
+            .map(|(_, (start, end))| match end {
+                Some(end) => (self.arrivals[start], self.arrivals[end], Phase::Done),
+                None => (self.arrivals[start], last, Phase::Running),
+            })
+            .collect();
+        // Folds start only once those before them ended, so the program can only have failed
+        // in or after the last.
+        if let Some((_, _, phase)) = phases.last_mut() {
+            match self.outcome {
+                None => {}
+                Some(Outcome::Exited(0)) => *phase = Phase::Done,
+                Some(_) => *phase = Phase::Failed,
+            }
+        }
+
+        let total = last - first;
+        let mut text = SmallVec::new();
+        let mut run: Option<(Phase, usize)> = None;
+        for column in 0..width {
+            let at = first + total * (2 * column + 1) as u32 / (2 * width) as u32;
+            let next = phases.partition_point(|(start, _, _)| *start <= at);
+            let phase = match next.checked_sub(1).map(|idx| phases[idx]) {
+                Some((_, end, phase)) if at <= end => phase,
+                _ => Phase::Idle,
+            };
+            run = match run {
+                Some((current, columns)) if current == phase => Some((phase, columns + 1)),
+                Some((current, columns)) => {
+                    text.push(fragment(current, columns));
+                    Some((phase, 1))
+                }
+                None => Some((phase, 1)),
+            };
+        }
+        if let Some((phase, columns)) = run {
+            text.push(fragment(phase, columns));
+        }
+
+        Some(line(text))
+    }
+}
+
+/// Write lines to the stdin of a child from a thread of its own, as writing blocks once the
+/// pipe is full and the child does not read. Stdin is closed once the sender is dropped.
+fn write_lines(mut stdin: ChildStdin) -> std::sync::mpsc::Sender<String> {
+    use std::io::Write;
+
+    let (sender, receiver) = std::sync::mpsc::channel::<String>();
+    std::thread::spawn(move || {
+        for line in receiver {
+            // The child may have stopped reading, which is not ours to report.
+            if writeln!(stdin, "{}", line).is_err() {
+                return;
+            }
+        }
+    });
+    sender
+}
+
+/// FNV-1a over all the lines of a fold, so that it stays the same between builds and runs.
+fn fold_hash(encapsulation: &Encapsulation) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    let mut feed = |line: &Text| {
+        for byte in line.bytes().chain(std::iter::once(b'\n')) {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+    };
+
+    feed(&encapsulation.start_line);
+    visit_lines(&encapsulation.content, &mut feed);
+    if let Some(end_line) = &encapsulation.end_line {
+        feed(end_line);
+    }
+
+    hash
+}
+
+/// Visit the lines of the given content in the order in which they arrived.
+pub(crate) fn visit_lines<'a>(content: &'a [Output], f: &mut impl FnMut(&'a Text)) {
+    for output in content {
+        match output {
+            Output::Lines(lines) => {
+                for line in lines {
+                    f(line);
+                }
+            }
+            Output::Encapsulation(encapsulation) => {
+                f(&encapsulation.start_line);
+                visit_lines(&encapsulation.content, f);
+                if let Some(end_line) = &encapsulation.end_line {
+                    f(end_line);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::display::{TruncationRule, TruncationRules};
+    use crate::MatchPair;
+    use regex::{Regex, RegexSet};
+    use std::cell::Cell;
+
+    /// A program that got the given lines, with folds from `start TITLE` to `end TITLE`.
+    fn program(lines: &[&str]) -> Program {
+        let (start, end) = (r"^start (.*)$", r"^end (.*)$");
+        let pairs = vec![MatchPair::new(
+            Regex::new(start).unwrap(),
+            Regex::new(end).unwrap(),
+        )];
+        let regex_set = RegexSet::new([start, end]).unwrap();
+        let sequence = Cell::new(0);
+        let matchers = Matchers {
+            match_pairs: &pairs,
+            regex_set: &regex_set,
+            sequence: &sequence,
+        };
+
+        let mut program = Program::new("make -j8 all".to_owned(), vec![]).with_index(true);
+        for (nr, line) in lines.iter().enumerate() {
+            let at = Duration::from_millis(nr as u64);
+            program.append_line(line.to_string(), at, &matchers);
+        }
+        program
+    }
+
+    fn options(ascii: bool, truncation: &str) -> DisplayOptions {
+        let mut rules = TruncationRules::default();
+        for kind in ["program", "title", "text"] {
+            let rule: TruncationRule = format!("{}={}", kind, truncation).parse().unwrap();
+            rules.set(&rule);
+        }
+        DisplayOptions {
+            ascii,
+            truncation: rules,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn renders_tiny_screens() {
+        let long = format!(
+            "/usr/src/{}/main.rs: {}",
+            "deep/".repeat(20),
+            "x".repeat(200)
+        );
+        let program = program(&[
+            "start outer",
+            "compiling\ta.c",
+            "start inner",
+            "émoji ✓ wide 日本語",
+            &long,
+            "end inner",
+            "tail",
+            "start open",
+            "last",
+        ]);
+
+        for (ascii, truncation) in [false, true]
+            .iter()
+            .flat_map(|ascii| ["end", "middle", "path"].iter().map(move |t| (*ascii, *t)))
+        {
+            let options = options(ascii, truncation);
+            for cx in 0..24 {
+                for extra in 0..12 {
+                    for count in 0..12 {
+                        let mut dd = program.calc_display_description(cx, extra, &options, &[]);
+                        let len = dd.lines().len();
+                        dd.reduce_to_count(count);
+                        assert_eq!(dd.lines().len(), len.min(count));
+
+                        for line in dd.lines() {
+                            // Whatever does not fit is cut down to the ellipsis.
+                            let least = line.indent + line.prefix.text.chars().count() + 3;
+                            assert!(
+                                line.width() <= cx.max(least),
+                                "{} columns at width {} ({}, {})",
+                                line.width(),
+                                cx,
+                                truncation,
+                                ascii
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn shapes() {
+        let ended = |lines: &[&str], outcome| {
+            let mut program = program(lines);
+            program.set_outcome(Some(outcome));
+            program.shape().unwrap()
+        };
+        let ok = Outcome::Exited(0);
+        let lines = [
+            "start build",
+            "start cc",
+            "x",
+            "end cc",
+            "end build",
+            "start test",
+        ];
+
+        assert!(program(&lines).shape().is_none());
+        assert_eq!(ended(&lines, ok), ended(&lines, ok));
+        // Lines outside of fold boundaries do not matter.
+        let other = [
+            "start build",
+            "start cc",
+            "y",
+            "z",
+            "end cc",
+            "end build",
+            "start test",
+        ];
+        assert_eq!(ended(&lines, ok), ended(&other, ok));
+
+        assert_ne!(ended(&lines, ok), ended(&lines, Outcome::Exited(1)));
+        assert_ne!(ended(&lines, ok), ended(&lines[..5], ok));
+        // Nor are folds of other titles or depths the same.
+        let other = [
+            "start build",
+            "start ld",
+            "x",
+            "end ld",
+            "end build",
+            "start test",
+        ];
+        assert_ne!(ended(&lines, ok), ended(&other, ok));
+        let other = [
+            "start build",
+            "end build",
+            "start cc",
+            "end cc",
+            "start test",
+        ];
+        assert_ne!(ended(&lines, ok), ended(&other, ok));
+    }
+
+    #[test]
+    fn responds_to_prompts() {
+        use std::io::Read;
+        use std::process::{Command, Stdio};
+
+        let expect = |prompt: &str, response: &str| Expect {
+            prompt: Regex::new(prompt).unwrap(),
+            response: response.to_owned(),
+        };
+        let mut program = Program::new("cat".to_owned(), vec![]).with_expect(vec![
+            expect("Proceed\\?", "y"),
+            expect("^Password:", "{secret}"),
+        ]);
+        let child = Command::new("cat")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        program.start(child, vec![], Default::default());
+
+        assert!(!program.respond("nothing to answer"));
+        assert!(program.respond("Password: "));
+        // A prompt is answered once while incomplete, and not again as it completes.
+        program.respond_prompt(0, "Proceed? ", false);
+        program.respond_prompt(0, "Proceed? [y/N]", true);
+        program.respond_prompt(1, "Proceed? [y/N]", true);
+
+        let mut stdout = program.child.as_mut().unwrap().stdout.take().unwrap();
+        program.responses = None;
+        let mut output = String::new();
+        stdout.read_to_string(&mut output).unwrap();
+        program.collect_outcome(true);
+        assert_eq!(output, "{secret}\ny\ny\n");
+    }
+
+    /// The timeline of a program that got one line per millisecond, in ASCII.
+    fn timeline(lines: &[&str], outcome: Option<Outcome>, columns: usize) -> String {
+        let mut program = program(lines);
+        program.set_outcome(outcome);
+        let options = options(true, "end");
+        let line = program.timeline(columns + 4, &options).unwrap();
+        line.text
+            .iter()
+            .map(|fragment| fragment.text.as_ref())
+            .collect()
+    }
+
+    #[test]
+    fn timeline_columns() {
+        let lines = ["start a", "x", "end a", "gap", "start b", "y"];
+        // Each column shows the middle of its share of the time from the first line to the
+        // last, here a twentieth of 5 milliseconds.
+        assert_eq!(timeline(&lines, None, 10), "####....>>");
+        assert_eq!(timeline(&lines, None, 5), "##..>");
+        assert_eq!(timeline(&lines[..4], None, 4), "###.");
+        assert_eq!(timeline(&["x", "y"], None, 3), "...");
+        assert_eq!(
+            program(&[]).timeline(10, &options(true, "end")).map(|_| ()),
+            None
+        );
+    }
+
+    #[test]
+    fn timeline_outcomes() {
+        let lines = ["start a", "x", "end a", "gap", "start b", "y"];
+        let failed = Some(Outcome::Exited(1));
+        assert_eq!(timeline(&lines, failed, 10), "####....XX");
+        let killed = Some(Outcome::Signaled(9));
+        assert_eq!(timeline(&lines, killed, 10), "####....XX");
+        assert_eq!(timeline(&lines, Some(Outcome::Exited(0)), 10), "####....##");
+
+        // Failing after the last fold ended still tells which one it was in last.
+        let lines = ["start a", "end a", "start b", "x", "end b"];
+        assert_eq!(timeline(&lines, failed, 8), "##..XXXX");
+        assert_eq!(timeline(&lines, None, 8), "##..####");
+    }
+}
@@ -0,0 +1,270 @@
+use super::program::Outcome;
+use super::session::Session;
+use super::{Error, Input, Key, Sender};
+use anyhow::Result;
+use async_std::io::BufReader;
+use async_std::os::unix::net::{UnixListener, UnixStream};
+use async_std::task::JoinHandle;
+use futures::SinkExt;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Sent from a session to its clients, one JSON document per line. A client first gets a
+/// snapshot of everything so far, and then the updates following it.
+///
+/// Programs are referred to by their index in the snapshot.
+#[derive(Serialize, Deserialize)]
+pub enum Message {
+    Snapshot(Session),
+    Line {
+        program: usize,
+        time: u64,
+        text: String,
+    },
+    Outcome {
+        program: usize,
+        outcome: Outcome,
+    },
+    /// A program that was added after the snapshot.
+    Program {
+        program: usize,
+        desc: String,
+        label: Option<String>,
+    },
+}
+
+impl Message {
+    pub fn line(program: Key, at: Duration, text: &str) -> Self {
+        Message::Line {
+            program,
+            time: at.as_millis() as u64,
+            text: text.to_owned(),
+        }
+    }
+
+    pub fn encode(&self) -> Result<String> {
+        let mut encoded = serde_json::to_string(self)?;
+        encoded.push('\n');
+        Ok(encoded)
+    }
+}
+
+/// A socket given on the command line, as `unix:/path`.
+#[derive(Debug, Clone)]
+pub struct Address(PathBuf);
+
+impl FromStr for Address {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            Some(path) if !path.is_empty() => Ok(Address(PathBuf::from(path))),
+            _ => Err(format!("unsupported address {}, expected unix:/path", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for Address {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "unix:{}", self.0.display())
+    }
+}
+
+impl Address {
+    /// Listen on the socket, replacing a stale one left behind by a previous run. Anything
+    /// else at the path is left alone, and fails the bind.
+    pub async fn bind(&self) -> Result<UnixListener> {
+        use std::os::unix::fs::FileTypeExt;
+
+        if let Ok(metadata) = std::fs::symlink_metadata(&self.0) {
+            if metadata.file_type().is_socket() {
+                let _ = std::fs::remove_file(&self.0);
+            }
+        }
+        Ok(UnixListener::bind(&self.0).await?)
+    }
+
+    pub fn remove(&self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// The clients connected to a session, each fed by a task of its own.
+#[derive(Default)]
+pub struct Clients {
+    senders: Vec<Sender<String>>,
+    feeds: Vec<JoinHandle<Result<()>>>,
+    /// Amount of programs sent to the clients with `Message::Program` or in their snapshots.
+    pub known: usize,
+}
+
+impl Clients {
+    /// Start feeding a newly connected client, beginning with the given snapshot.
+    pub fn add(&mut self, stream: UnixStream, snapshot: Session) -> Result<()> {
+        let (sender, receiver) = futures::channel::mpsc::unbounded();
+        let _ = sender.unbounded_send(Message::Snapshot(snapshot).encode()?);
+        self.senders.push(sender);
+        self.feeds
+            .push(async_std::task::spawn(feed(stream, receiver)));
+
+        Ok(())
+    }
+
+    /// Send to all clients, forgetting those that went away.
+    pub fn send(&mut self, message: &Message) -> Result<()> {
+        if !self.senders.is_empty() {
+            let encoded = message.encode()?;
+            self.senders
+                .retain(|sender| sender.unbounded_send(encoded.clone()).is_ok());
+        }
+
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.senders.is_empty()
+    }
+
+    /// Wait for everything sent so far to reach the clients.
+    pub async fn finish(self) {
+        drop(self.senders);
+        for feed in self.feeds {
+            let _ = feed.await;
+        }
+    }
+}
+
+/// The next client connecting, never resolving if there is nothing to listen on.
+pub async fn accept(listener: &Option<UnixListener>) -> std::io::Result<UnixStream> {
+    match listener {
+        Some(listener) => listener.accept().await.map(|(stream, _)| stream),
+        None => async_std::future::pending().await,
+    }
+}
+
+/// Where the socket of a named session lives.
+pub fn socket_path(name: &str) -> Result<PathBuf> {
+    let dir = match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => PathBuf::from(dir),
+        None => {
+            let dir = PathBuf::from(format!("/tmp/foldity-{}", unsafe { libc::getuid() }));
+            if !dir.exists() {
+                use std::os::unix::fs::DirBuilderExt;
+                std::fs::DirBuilder::new().mode(0o700).create(&dir)?;
+            }
+            dir
+        }
+    };
+
+    Ok(dir.join(format!("foldity-{}.sock", name)))
+}
+
+/// Detach from the terminal, continuing in a background process. This must happen before
+/// any threads are started, as only the calling thread survives the fork.
+pub fn daemonize(name: &str) -> Result<()> {
+    let path = socket_path(name)?;
+    if std::os::unix::net::UnixStream::connect(&path).is_ok() {
+        return Err(Error::SessionExists(name.to_owned()).into());
+    }
+
+    match unsafe { libc::fork() } {
+        -1 => return Err(std::io::Error::last_os_error().into()),
+        0 => {}
+        _ => {
+            println!(
+                "Started session {}, attach with: foldity attach {}",
+                name, name
+            );
+            std::process::exit(0);
+        }
+    }
+
+    unsafe {
+        libc::setsid();
+
+        let null = libc::open(b"/dev/null\0".as_ptr() as *const libc::c_char, libc::O_RDWR);
+        if null >= 0 {
+            libc::dup2(null, libc::STDOUT_FILENO);
+            libc::dup2(null, libc::STDERR_FILENO);
+            libc::close(null);
+        }
+    }
+
+    Ok(())
+}
+
+/// Connect to a named session, returning its state so far, and the stream of updates that
+/// follow it.
+pub async fn connect(name: &str) -> Result<(Session, BufReader<UnixStream>)> {
+    let path = socket_path(name)?;
+    let stream = UnixStream::connect(&path)
+        .await
+        .map_err(|_| Error::SessionNotFound(name.to_owned()))?;
+
+    handshake(stream, name).await
+}
+
+/// Like `connect`, for a session mirrored with `--mirror`.
+pub async fn connect_to(address: &Address) -> Result<(Session, BufReader<UnixStream>)> {
+    let stream = UnixStream::connect(&address.0)
+        .await
+        .map_err(|_| Error::MirrorNotFound(address.to_string()))?;
+
+    handshake(stream, &address.to_string()).await
+}
+
+async fn handshake(stream: UnixStream, name: &str) -> Result<(Session, BufReader<UnixStream>)> {
+    use async_std::prelude::*;
+
+    let mut reader = BufReader::new(stream);
+    let mut first = String::new();
+    reader.read_line(&mut first).await?;
+
+    match serde_json::from_str(&first)? {
+        Message::Snapshot(session) => {
+            session.check_version(name)?;
+            Ok((session, reader))
+        }
+        _ => Err(Error::SessionProtocol(name.to_owned()).into()),
+    }
+}
+
+/// Relay the updates of a session to the broker, until it goes away.
+pub async fn relay(reader: BufReader<UnixStream>, mut sender: Sender<(Key, Input)>) -> Result<()> {
+    use async_std::prelude::*;
+
+    let mut lines = reader.lines();
+
+    while let Some(line) = lines.next().await {
+        let input = match serde_json::from_str(&line?)? {
+            Message::Line {
+                program,
+                time,
+                text,
+            } => (program, Input::Remote(Duration::from_millis(time), text)),
+            Message::Outcome { program, outcome } => (program, Input::RemoteOutcome(outcome)),
+            Message::Program {
+                program,
+                desc,
+                label,
+            } => (program, Input::RemoteProgram(desc, label)),
+            Message::Snapshot(_) => continue,
+        };
+        sender.send(input).await?;
+    }
+
+    Ok(())
+}
+
+/// Forward encoded messages to a connected client, until either side goes away.
+pub async fn feed(mut stream: UnixStream, mut receiver: super::Receiver<String>) -> Result<()> {
+    use async_std::prelude::*;
+
+    while let Some(message) = receiver.next().await {
+        stream.write_all(message.as_bytes()).await?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,162 @@
+use regex::Regex;
+use std::borrow::Cow;
+use std::str::FromStr;
+
+/// A sed-like substitution given with `--rewrite`, e.g. `s/\/home\/[^\/]*/~/g`.
+///
+/// Any character following the `s` may serve as the delimiter. In the replacement, `&` stands
+/// for the whole match and `\1` to `\9` for the groups. Only the first match is replaced,
+/// unless the `g` flag is given.
+#[derive(Debug, Clone)]
+pub struct Rewrite {
+    regex: Regex,
+    /// In the syntax of `Regex::replace`.
+    replacement: String,
+    global: bool,
+}
+
+impl FromStr for Rewrite {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid rewrite rule {}, expected s/REGEX/REPLACEMENT/", s);
+
+        let mut chars = s.chars();
+        if chars.next() != Some('s') {
+            return Err(invalid());
+        }
+        let delimiter = chars.next().ok_or_else(invalid)?;
+        let parts = split_unescaped(chars.as_str(), delimiter);
+        let (pattern, replacement, flags) = match parts.as_slice() {
+            [pattern, replacement, flags] => (pattern, replacement, flags),
+            _ => return Err(invalid()),
+        };
+
+        let global = match flags.as_str() {
+            "" => false,
+            "g" => true,
+            _ => return Err(format!("unknown flags {} in rewrite rule {}", flags, s)),
+        };
+        let regex = Regex::new(pattern).map_err(|e| e.to_string())?;
+
+        Ok(Rewrite {
+            regex,
+            replacement: sed_replacement(replacement),
+            global,
+        })
+    }
+}
+
+impl Rewrite {
+    pub fn apply<'a>(&self, s: &'a str) -> Cow<'a, str> {
+        if self.global {
+            self.regex.replace_all(s, self.replacement.as_str())
+        } else {
+            self.regex.replace(s, self.replacement.as_str())
+        }
+    }
+}
+
+/// Apply all rules in turn, each to the outcome of the previous one.
+pub fn apply_all(rules: &[Rewrite], s: String) -> String {
+    rules.iter().fold(s, |s, rule| match rule.apply(&s) {
+        Cow::Borrowed(_) => s,
+        Cow::Owned(rewritten) => rewritten,
+    })
+}
+
+/// Split on the delimiter where it is not escaped with a backslash, dropping the backslash
+/// of escaped delimiters and keeping all other escapes as they are.
+fn split_unescaped(s: &str, delimiter: char) -> Vec<String> {
+    let mut parts = vec![String::new()];
+    let mut chars = s.chars();
+
+    while let Some(c) = chars.next() {
+        let part = parts.last_mut().unwrap();
+        match c {
+            '\\' => match chars.next() {
+                Some(next) if next == delimiter => part.push(next),
+                Some(next) => {
+                    part.push('\\');
+                    part.push(next);
+                }
+                None => part.push('\\'),
+            },
+            c if c == delimiter => parts.push(String::new()),
+            c => part.push(c),
+        }
+    }
+
+    parts
+}
+
+/// Translate `&`, `\1` and escapes from sed syntax to that of `Regex::replace`.
+fn sed_replacement(s: &str) -> String {
+    let mut replacement = String::with_capacity(s.len());
+    let mut chars = s.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '&' => replacement.push_str("${0}"),
+            '$' => replacement.push_str("$$"),
+            '\\' => match chars.next() {
+                Some(digit @ '0'..='9') => {
+                    replacement.push_str("${");
+                    replacement.push(digit);
+                    replacement.push('}');
+                }
+                Some('$') => replacement.push_str("$$"),
+                Some(other) => replacement.push(other),
+                None => replacement.push('\\'),
+            },
+            c => replacement.push(c),
+        }
+    }
+
+    replacement
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rewrite(rule: &str, s: &str) -> String {
+        rule.parse::<Rewrite>().unwrap().apply(s).into_owned()
+    }
+
+    #[test]
+    fn parsing() {
+        assert_eq!(rewrite("s/a/b/", "aaa"), "baa");
+        assert_eq!(rewrite("s/a/b/g", "aaa"), "bbb");
+        assert_eq!(rewrite("s|/home/[^/]*|~|", "/home/user/src"), "~/src");
+        assert_eq!(rewrite(r"s/\/tmp/T/", "/tmp/x"), "T/x");
+        assert_eq!(rewrite(r"s/\d+/N/g", "a1b22"), "aNbN");
+
+        assert!("x/a/b/".parse::<Rewrite>().is_err());
+        assert!("s".parse::<Rewrite>().is_err());
+        assert!("s/a/b".parse::<Rewrite>().is_err());
+        assert!("s/a/b/c/".parse::<Rewrite>().is_err());
+        assert!("s/a/b/i".parse::<Rewrite>().is_err());
+        assert!("s/(/b/".parse::<Rewrite>().is_err());
+    }
+
+    #[test]
+    fn replacements() {
+        assert_eq!(sed_replacement("[&]"), "[${0}]");
+        assert_eq!(sed_replacement(r"\2-\1"), "${2}-${1}");
+        assert_eq!(sed_replacement("$1"), "$$1");
+        assert_eq!(sed_replacement(r"\$\&\\"), r"$$&\");
+        assert_eq!(sed_replacement("a\\"), "a\\");
+
+        assert_eq!(rewrite(r"s/(\w+)=(\w+)/\2=\1/", "a=b"), "b=a");
+        assert_eq!(rewrite("s/b/<&>/", "abc"), "a<b>c");
+        assert_eq!(rewrite("s/b/$1/", "abc"), "a$1c");
+    }
+
+    #[test]
+    fn rules_in_turn() {
+        let rules: Vec<Rewrite> = vec!["s/a/b/g".parse().unwrap(), "s/b/c/".parse().unwrap()];
+        assert_eq!(apply_all(&rules, "aa".to_owned()), "cb");
+        assert_eq!(apply_all(&[], "aa".to_owned()), "aa");
+    }
+}
@@ -0,0 +1,425 @@
+use super::filter::{Filter, Subject};
+use super::program::{Outcome, Program};
+use super::{Error, MatchPair, Output};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use slab::Slab;
+use std::collections::{BTreeMap, HashSet};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+
+/// Bumped on any incompatible change to the structures below.
+pub const VERSION: u32 = 1;
+
+/// Everything needed to rebuild the fold tree of a past session: the matchers and the raw
+/// lines of each program, from which the folds are derived again when loading.
+#[derive(Serialize, Deserialize)]
+pub struct Session {
+    pub version: u32,
+    pub match_pairs: Vec<SessionPair>,
+    pub programs: Vec<SessionProgram>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SessionPair {
+    pub start: String,
+    pub end: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SessionProgram {
+    pub desc: String,
+    #[serde(default)]
+    pub label: Option<String>,
+    pub outcome: Option<Outcome>,
+    pub lines: Vec<SessionLine>,
+    /// Derived from the lines, kept so that other runs can be compared without the matchers.
+    #[serde(default)]
+    pub folds: Vec<SessionFold>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SessionLine {
+    /// Milliseconds since the start of the session.
+    pub time: u64,
+    pub text: String,
+    /// Identifies the program that printed the line among those of the capturing session,
+    /// even where programs were left out by a filter.
+    #[serde(default)]
+    pub source: usize,
+    /// Position of the line in the output of the program, from 1, counting lines left out.
+    #[serde(default)]
+    pub number: usize,
+    /// Position of the line among the lines of all programs in order of arrival, from 1, as
+    /// lines of different programs can arrive within the same millisecond. 0 if unknown.
+    #[serde(default)]
+    pub seq: u64,
+}
+
+/// A fold, in the order in which the folds started.
+#[derive(Serialize, Deserialize)]
+pub struct SessionFold {
+    pub title: String,
+    /// Like in `SessionLine`, for the program of the fold.
+    #[serde(default)]
+    pub source: usize,
+    /// Numbers of the lines starting and ending the fold, like in `SessionLine`.
+    #[serde(default)]
+    pub start: usize,
+    #[serde(default)]
+    pub end: Option<usize>,
+    /// When the fold started, in milliseconds since the start of the session.
+    #[serde(default)]
+    pub time: u64,
+    /// Only for folds that ended.
+    pub hash: Option<u64>,
+    /// Named captures of the start regex besides `M`.
+    #[serde(default)]
+    pub metadata: BTreeMap<String, String>,
+}
+
+/// Capture the current state of the given programs.
+pub(crate) fn snapshot(match_pairs: &[MatchPair], programs: &Slab<Program>) -> Session {
+    capture(match_pairs, programs, None)
+}
+
+/// Like `snapshot`, but with a filter only the folds matching it are kept, along with the
+/// start and end lines of the folds enclosing them. Programs left with no lines are dropped.
+fn capture(
+    match_pairs: &[MatchPair],
+    programs: &Slab<Program>,
+    filter: Option<&Filter>,
+) -> Session {
+    let match_pairs = pairs(match_pairs);
+
+    let programs = programs
+        .iter()
+        .filter_map(|(source, program)| {
+            let (kept, starts) = match filter {
+                Some(filter) => {
+                    let (kept, starts) = kept_lines(program, filter);
+                    if !kept.contains(&true) {
+                        return None;
+                    }
+                    (Some(kept), starts)
+                }
+                None => (None, vec![]),
+            };
+            let is_kept = |nr: usize| match &kept {
+                Some(kept) => kept[nr],
+                None => true,
+            };
+
+            let lines = lines(program, source, is_kept);
+            let folds = folds(program, source, |idx| match starts.get(idx) {
+                Some(start) => is_kept(*start),
+                None => true,
+            });
+
+            Some(SessionProgram {
+                lines,
+                folds,
+                ..header(program)
+            })
+        })
+        .collect();
+
+    Session {
+        version: VERSION,
+        match_pairs,
+        programs,
+    }
+}
+
+pub(crate) fn pairs(match_pairs: &[MatchPair]) -> Vec<SessionPair> {
+    match_pairs
+        .iter()
+        .map(|pair| SessionPair {
+            start: pair.start.as_str().to_owned(),
+            end: pair.end.as_str().to_owned(),
+        })
+        .collect()
+}
+
+/// A program with neither lines nor folds.
+pub(crate) fn header(program: &Program) -> SessionProgram {
+    SessionProgram {
+        desc: program.desc().to_owned(),
+        label: program.label().map(str::to_owned),
+        outcome: program.outcome(),
+        lines: vec![],
+        folds: vec![],
+    }
+}
+
+fn time(program: &Program, nr: usize) -> u64 {
+    let at = program.arrivals().get(nr);
+    at.map_or(0, |at| at.as_millis() as u64)
+}
+
+/// The lines of the program that are kept, given their number in arrival order from 0.
+pub(crate) fn lines(
+    program: &Program,
+    source: usize,
+    is_kept: impl Fn(usize) -> bool,
+) -> Vec<SessionLine> {
+    let mut lines = vec![];
+    let mut nr = 0;
+    program.for_each_line(|text| {
+        if is_kept(nr) {
+            lines.push(SessionLine {
+                time: time(program, nr),
+                text: text.clone(),
+                source,
+                number: nr + 1,
+                seq: program.sequence().get(nr).copied().unwrap_or(0),
+            });
+        }
+        nr += 1;
+    });
+    lines
+}
+
+/// The line that the program kept last, given its text.
+pub(crate) fn last_line(program: &Program, source: usize, text: String) -> SessionLine {
+    let nr = program.arrivals().len().saturating_sub(1);
+    SessionLine {
+        time: time(program, nr),
+        text,
+        source,
+        number: nr + 1,
+        seq: program.sequence().get(nr).copied().unwrap_or(0),
+    }
+}
+
+/// The folds of the program that are kept, given their index in the order of
+/// `Program::fold_paths`.
+pub(crate) fn folds(
+    program: &Program,
+    source: usize,
+    is_kept: impl Fn(usize) -> bool,
+) -> Vec<SessionFold> {
+    program
+        .fold_paths()
+        .iter()
+        .zip(program.fold_spans())
+        .enumerate()
+        .filter(|(idx, _)| is_kept(*idx))
+        .filter_map(|(_, (path, span))| Some((program.fold(path)?, span)))
+        .map(|(fold, (start, end))| SessionFold {
+            title: fold.start_title.clone(),
+            source,
+            start: start + 1,
+            end: end.map(|end| end + 1),
+            time: time(program, start),
+            hash: fold.hash,
+            metadata: fold.metadata.iter().cloned().collect(),
+        })
+        .collect()
+}
+
+/// Which lines of the program, by number in arrival order, are kept by the filter. Also
+/// returns where each fold starts, in the order of `Program::fold_paths`.
+fn kept_lines(program: &Program, filter: &Filter) -> (Vec<bool>, Vec<usize>) {
+    struct Walk<'a> {
+        program: &'a Program,
+        filter: &'a Filter,
+        outcome: &'static str,
+        kept: Vec<bool>,
+        starts: Vec<usize>,
+        nr: usize,
+    }
+
+    impl<'a> Walk<'a> {
+        /// Returns whether anything in the content is kept.
+        fn content(&mut self, content: &[Output], inside_kept: bool) -> bool {
+            let mut any = false;
+
+            for output in content {
+                match output {
+                    Output::Lines(lines) => {
+                        for _ in lines {
+                            self.kept[self.nr] = inside_kept;
+                            self.nr += 1;
+                        }
+                        any |= inside_kept && !lines.is_empty();
+                    }
+                    Output::Encapsulation(encapsulation) => {
+                        let start = self.nr;
+                        self.starts.push(start);
+
+                        let ended = encapsulation.end_line.is_some() as usize;
+                        let last = start + encapsulation.nr_lines + ended;
+                        let arrivals = self.program.arrivals();
+                        let subject = Subject {
+                            program: self.program.desc(),
+                            label: self.program.label(),
+                            title: &encapsulation.start_title,
+                            outcome: self.outcome,
+                            duration: arrivals[last.min(arrivals.len() - 1)]
+                                .saturating_sub(arrivals[start]),
+                            metadata: &encapsulation.metadata,
+                        };
+                        let matched = inside_kept || self.filter.matches(&subject);
+
+                        self.nr += 1;
+                        let inner = self.content(&encapsulation.content, matched);
+                        self.kept[start] = matched || inner;
+                        if ended == 1 {
+                            self.kept[self.nr] = matched || inner;
+                            self.nr += 1;
+                        }
+                        any |= matched || inner;
+                    }
+                }
+            }
+
+            any
+        }
+    }
+
+    let outcome = match program.outcome() {
+        None => "running",
+        Some(Outcome::Exited(0)) => "ok",
+        Some(Outcome::Exited(_)) => "failed",
+        Some(Outcome::Signaled(_)) => "killed",
+    };
+    let mut walk = Walk {
+        program,
+        filter,
+        outcome,
+        kept: vec![false; program.arrivals().len()],
+        starts: vec![],
+        nr: 0,
+    };
+    walk.content(program.content(), false);
+
+    (walk.kept, walk.starts)
+}
+
+pub(crate) fn save(
+    path: &str,
+    match_pairs: &[MatchPair],
+    programs: &Slab<Program>,
+    filter: Option<&Filter>,
+) -> Result<()> {
+    let session = capture(match_pairs, programs, filter);
+    let mut writer = BufWriter::new(File::create(path)?);
+    serde_json::to_writer(&mut writer, &session)?;
+    writer.flush()?;
+
+    Ok(())
+}
+
+pub fn load(path: &str) -> Result<Session> {
+    let session: Session = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+    session.check_version(path)?;
+
+    Ok(session)
+}
+
+impl Session {
+    pub fn check_version(&self, origin: &str) -> Result<()> {
+        if self.version != VERSION {
+            return Err(Error::SessionVersion(origin.to_owned(), self.version).into());
+        }
+
+        Ok(())
+    }
+
+    /// Hashes of all the folds that ended, over all programs.
+    pub fn fold_hashes(&self) -> HashSet<u64> {
+        self.programs
+            .iter()
+            .flat_map(|program| program.folds.iter().filter_map(|fold| fold.hash))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Matchers;
+    use regex::{Regex, RegexSet};
+    use std::cell::Cell;
+    use std::time::Duration;
+
+    /// Feed lines to programs as they would arrive, with folds from `start TITLE` to
+    /// `end TITLE`.
+    fn programs(lines: &[(usize, u64, &str)]) -> (Vec<MatchPair>, Slab<Program>) {
+        let (start, end) = (r"^start (.*)$", r"^end (.*)$");
+        let match_pairs = vec![MatchPair::new(
+            Regex::new(start).unwrap(),
+            Regex::new(end).unwrap(),
+        )];
+        let regex_set = RegexSet::new([start, end]).unwrap();
+        let sequence = Cell::new(0);
+        let matchers = Matchers {
+            match_pairs: &match_pairs,
+            regex_set: &regex_set,
+            sequence: &sequence,
+        };
+
+        let mut programs = Slab::new();
+        for (key, at, line) in lines {
+            while programs.len() <= *key {
+                programs.insert(Program::new(format!("program {}", key), vec![]));
+            }
+            let at = Duration::from_millis(*at);
+            programs[*key].append_line(line.to_string(), at, &matchers);
+        }
+        (match_pairs, programs)
+    }
+
+    #[test]
+    fn save_and_load() {
+        let (match_pairs, programs) = programs(&[
+            (0, 5, "start build"),
+            (1, 5, "warming up"),
+            (0, 5, "compiling"),
+            (1, 7, "end nothing"),
+            (0, 9, "end build"),
+            (1, 9, "start test"),
+        ]);
+
+        let path = std::env::temp_dir().join(format!("foldity-test-{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        save(path, &match_pairs, &programs, None).unwrap();
+        let loaded = load(path);
+        let _ = std::fs::remove_file(path);
+        let loaded = loaded.unwrap();
+
+        assert_eq!(loaded.match_pairs[0].start, r"^start (.*)$");
+        let lines: Vec<_> = loaded.programs[0]
+            .lines
+            .iter()
+            .map(|line| (line.text.as_str(), line.time, line.number, line.seq))
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                ("start build", 5, 1, 1),
+                ("compiling", 5, 2, 3),
+                ("end build", 9, 3, 4),
+            ]
+        );
+        // The unmatched end line is not kept, and the numbering goes on without it.
+        let lines: Vec<_> = loaded.programs[1]
+            .lines
+            .iter()
+            .map(|line| (line.text.as_str(), line.source, line.seq))
+            .collect();
+        assert_eq!(lines, [("warming up", 1, 2), ("start test", 1, 5)]);
+
+        let folds = &loaded.programs[0].folds;
+        assert_eq!(folds.len(), 1);
+        assert_eq!(folds[0].title, "build");
+        assert_eq!(
+            (folds[0].start, folds[0].end, folds[0].time),
+            (1, Some(3), 5)
+        );
+        assert!(folds[0].hash.is_some());
+        let open = &loaded.programs[1].folds[0];
+        assert_eq!((open.start, open.end, open.hash), (2, None, None));
+    }
+}
@@ -0,0 +1,270 @@
+use super::session::{self, Session, SessionLine, SessionPair, SessionProgram};
+use anyhow::Result;
+use std::collections::{BTreeMap, HashSet};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// Significant events bring the next snapshot forward, but not closer than this to the last.
+const MIN_GAP: Duration = Duration::from_secs(1);
+
+/// Writes snapshots of the live session to a directory for `--spool`, so that what a run had
+/// reached can be told even if the machine crashed under it. Snapshots are numbered, written
+/// in full before being renamed into place, and only the last few are kept.
+///
+/// The session is kept by the writer, which is only told what changed since the last
+/// snapshot, so that taking one does not hold up the rest.
+pub struct Spool {
+    interval: Duration,
+    last: Option<Instant>,
+    /// Whether something significant happened since the last snapshot.
+    urgent: bool,
+    /// Programs that the writer heard of.
+    known: HashSet<usize>,
+    /// Programs known to have finished.
+    finished: HashSet<usize>,
+    changes: BTreeMap<usize, Changes>,
+    /// Snapshots go to a thread of their own, as syncing them to disk can take a while.
+    sender: Option<Sender<Update>>,
+    writer: Option<JoinHandle<Result<()>>>,
+}
+
+/// What changed in a program since the last snapshot.
+#[derive(Default)]
+struct Changes {
+    /// The writer needs all of the lines, e.g. for a program that it did not hear of yet.
+    full: bool,
+    /// The lines that arrived, unless `full`.
+    lines: Vec<SessionLine>,
+}
+
+/// Brings the session of the writer up to date.
+pub struct Update {
+    match_pairs: Vec<SessionPair>,
+    /// Programs that changed, along with their key, and with only the lines that arrived in
+    /// them unless all are needed.
+    programs: Vec<(usize, Changes, SessionProgram)>,
+}
+
+impl Spool {
+    pub fn new(dir: &str, interval: Duration, keep: usize) -> Result<Self> {
+        std::fs::create_dir_all(dir)?;
+
+        // Continue the numbering of earlier runs, so that the latest snapshot is the last.
+        let mut seq = 0;
+        for entry in std::fs::read_dir(dir)? {
+            let name = entry?.file_name();
+            let number = name
+                .to_str()
+                .and_then(|name| name.strip_prefix("snapshot-")?.strip_suffix(".json"))
+                .and_then(|number| number.parse::<u64>().ok());
+            if let Some(number) = number {
+                seq = seq.max(number + 1);
+            }
+        }
+
+        let dir = PathBuf::from(dir);
+        let keep = keep.max(1) as u64;
+        let (sender, receiver) = channel::<Update>();
+        let writer = std::thread::spawn(move || {
+            let mut writer = Writer::default();
+            while writer.receive(&receiver) {
+                write(&dir, seq, &writer.session)?;
+                if let Some(old) = seq.checked_sub(keep) {
+                    let _ = std::fs::remove_file(dir.join(format!("snapshot-{:06}.json", old)));
+                }
+                seq += 1;
+            }
+            Ok(())
+        });
+
+        Ok(Spool {
+            interval,
+            last: None,
+            urgent: false,
+            known: HashSet::new(),
+            finished: HashSet::new(),
+            changes: BTreeMap::new(),
+            sender: Some(sender),
+            writer: Some(writer),
+        })
+    }
+
+    /// A fold started or ended.
+    pub fn significant(&mut self) {
+        self.urgent = true;
+    }
+
+    /// Take note of a program as it currently is. Programs finishing is significant.
+    pub fn program(&mut self, key: usize, finished: bool) {
+        if self.known.insert(key) {
+            self.resend(key);
+        }
+        if finished && self.finished.insert(key) {
+            self.changes.entry(key).or_default();
+            self.urgent = true;
+        }
+    }
+
+    /// A line that the program kept.
+    pub fn line(&mut self, line: SessionLine) {
+        let changes = self.changes.entry(line.source).or_default();
+        if !changes.full {
+            changes.lines.push(line);
+        }
+    }
+
+    /// The program has to be written anew, e.g. as its lines were replaced.
+    pub fn resend(&mut self, key: usize) {
+        let changes = self.changes.entry(key).or_default();
+        changes.full = true;
+        changes.lines.clear();
+    }
+
+    /// When the next snapshot is due.
+    pub fn due_at(&self) -> Instant {
+        let last = match self.last {
+            Some(last) => last,
+            None => return Instant::now(),
+        };
+        last + if self.urgent { MIN_GAP } else { self.interval }
+    }
+
+    pub fn is_due(&self) -> bool {
+        self.due_at() <= Instant::now()
+    }
+
+    /// The changes since the last snapshot, for the caller to fill in with `program` giving
+    /// each program as it is now without its lines, and `lines` all of its lines.
+    pub fn update(
+        &mut self,
+        match_pairs: Vec<SessionPair>,
+        mut program: impl FnMut(usize) -> Option<SessionProgram>,
+        mut lines: impl FnMut(usize) -> Vec<SessionLine>,
+    ) -> Update {
+        let programs = std::mem::take(&mut self.changes)
+            .into_iter()
+            .filter_map(|(key, mut changes)| {
+                let mut program = program(key)?;
+                program.lines = if changes.full {
+                    lines(key)
+                } else {
+                    std::mem::take(&mut changes.lines)
+                };
+                Some((key, changes, program))
+            })
+            .collect();
+
+        Update {
+            match_pairs,
+            programs,
+        }
+    }
+
+    /// Hand a snapshot to the writer. Should it still be busy with the previous one, the
+    /// changes are written together once it is done.
+    pub fn write(&mut self, update: Update) -> Result<()> {
+        let sender = match &self.sender {
+            Some(sender) => sender,
+            None => return Ok(()),
+        };
+
+        if sender.send(update).is_err() {
+            return self.finish(None);
+        }
+        self.urgent = false;
+        self.last = Some(Instant::now());
+        Ok(())
+    }
+
+    /// Write a last snapshot, if given, and wait for the writer to be done.
+    pub fn finish(&mut self, update: Option<Update>) -> Result<()> {
+        if let (Some(sender), Some(update)) = (self.sender.take(), update) {
+            // Should the writer have failed, joining it tells why.
+            let _ = sender.send(update);
+        }
+        drop(self.sender.take());
+
+        match self.writer.take().map(JoinHandle::join) {
+            Some(Ok(res)) => res,
+            Some(Err(panic)) => std::panic::resume_unwind(panic),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The session as of the changes received so far.
+struct Writer {
+    session: Session,
+    /// The key of each program of the session.
+    keys: Vec<usize>,
+}
+
+impl Default for Writer {
+    fn default() -> Self {
+        Writer {
+            session: Session {
+                version: session::VERSION,
+                match_pairs: vec![],
+                programs: vec![],
+            },
+            keys: vec![],
+        }
+    }
+}
+
+impl Writer {
+    /// Wait for changes, and take in those that queued up meanwhile too. Returns whether
+    /// there are any.
+    fn receive(&mut self, receiver: &Receiver<Update>) -> bool {
+        let update = match receiver.recv() {
+            Ok(update) => update,
+            Err(_) => return false,
+        };
+        self.apply(update);
+        while let Ok(update) = receiver.try_recv() {
+            self.apply(update);
+        }
+        true
+    }
+
+    fn apply(&mut self, update: Update) {
+        self.session.match_pairs = update.match_pairs;
+
+        for (key, changes, mut program) in update.programs {
+            let idx = match self.keys.iter().position(|known| *known == key) {
+                Some(idx) => idx,
+                None => {
+                    self.keys.push(key);
+                    self.session.programs.push(program);
+                    continue;
+                }
+            };
+            let old = &mut self.session.programs[idx];
+            if !changes.full {
+                let mut lines = std::mem::take(&mut old.lines);
+                lines.append(&mut program.lines);
+                program.lines = lines;
+            }
+            *old = program;
+        }
+    }
+}
+
+fn write(dir: &Path, seq: u64, session: &Session) -> Result<()> {
+    let path = dir.join(format!("snapshot-{:06}.json", seq));
+    let partial = dir.join("snapshot.partial");
+
+    let file = File::create(&partial)?;
+    let mut writer = BufWriter::new(&file);
+    serde_json::to_writer(&mut writer, session)?;
+    writer.flush()?;
+    drop(writer);
+    // The point is surviving a crash, which the page cache would not.
+    file.sync_all()?;
+    std::fs::rename(&partial, &path)?;
+    Ok(())
+}
@@ -0,0 +1,204 @@
+use super::Error;
+use anyhow::Result;
+use serde::Deserialize;
+use std::process::{Command, Stdio};
+
+const LATEST_RELEASE: &str = "https://api.github.com/repos/da-x/foldity/releases/latest";
+
+/// The parts of a release that we look at, as described by the GitHub API.
+#[derive(Deserialize)]
+struct Release {
+    tag_name: String,
+    assets: Vec<Asset>,
+}
+
+#[derive(Deserialize)]
+struct Asset {
+    name: String,
+    browser_download_url: String,
+}
+
+impl Release {
+    fn version(&self) -> Vec<u64> {
+        parse_version(&self.tag_name)
+    }
+
+    fn asset(&self, name: &str) -> Option<&Asset> {
+        self.assets.iter().find(|asset| asset.name == name)
+    }
+}
+
+/// The name of the binary for the platform we are running on, e.g. `foldity-x86_64-linux`.
+/// Its checksum is published alongside as an asset of the same name ending with `.sha256`.
+fn asset_name() -> String {
+    format!(
+        "foldity-{}-{}",
+        std::env::consts::ARCH,
+        std::env::consts::OS
+    )
+}
+
+/// The first word of a `sha256sum` style line, if it looks like a SHA-256 digest.
+fn parse_checksum(s: &str) -> Option<String> {
+    let word = s.split_whitespace().next()?;
+    if word.len() == 64 && word.chars().all(|c| c.is_ascii_hexdigit()) {
+        Some(word.to_ascii_lowercase())
+    } else {
+        None
+    }
+}
+
+/// Like downloads, hashing is left to a standard tool.
+fn sha256sum(path: &std::path::Path) -> Result<Option<String>> {
+    let output = Command::new("sha256sum")
+        .arg(path)
+        .stderr(Stdio::inherit())
+        .output()?;
+    Ok(parse_checksum(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// `1.2.3` and `v1.2.3` both become `[1, 2, 3]`.
+fn parse_version(s: &str) -> Vec<u64> {
+    s.trim_start_matches('v')
+        .split('.')
+        .map(|part| part.parse().unwrap_or(0))
+        .collect()
+}
+
+fn current_version() -> Vec<u64> {
+    parse_version(env!("CARGO_PKG_VERSION"))
+}
+
+/// Downloads are done with `curl`, so that no TLS stack needs to be built in.
+fn curl(url: &str, max_time: u32) -> Command {
+    let mut command = Command::new("curl");
+    command
+        .args(["--silent", "--show-error", "--fail", "--location"])
+        .arg("--max-time")
+        .arg(max_time.to_string())
+        .arg(url);
+    command
+}
+
+fn latest_release(max_time: u32) -> Result<Release> {
+    let output = curl(LATEST_RELEASE, max_time)
+        .arg("--header")
+        .arg("Accept: application/vnd.github+json")
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|e| Error::UpdateQuery(e.to_string()))?;
+
+    if !output.status.success() {
+        return Err(Error::UpdateQuery(output.status.to_string()).into());
+    }
+
+    Ok(serde_json::from_slice(&output.stdout)?)
+}
+
+/// Replace the running executable with the binary of the latest release, if it is newer.
+pub fn self_update() -> Result<()> {
+    let release = latest_release(30)?;
+    if release.version() <= current_version() {
+        println!(
+            "foldity {} is the latest version",
+            env!("CARGO_PKG_VERSION")
+        );
+        return Ok(());
+    }
+
+    let name = asset_name();
+    let asset = release
+        .asset(&name)
+        .ok_or_else(|| Error::UpdateNoAsset(release.tag_name.clone(), name.clone()))?;
+    let no_checksum = || Error::UpdateNoChecksum(release.tag_name.clone(), name.clone());
+    let checksum = release
+        .asset(&format!("{}.sha256", name))
+        .ok_or_else(no_checksum)?;
+
+    let output = curl(&checksum.browser_download_url, 30)
+        .stderr(Stdio::inherit())
+        .output()?;
+    if !output.status.success() {
+        return Err(Error::UpdateDownload(output.status.to_string()).into());
+    }
+    let checksum =
+        parse_checksum(&String::from_utf8_lossy(&output.stdout)).ok_or_else(no_checksum)?;
+
+    // Download next to the executable, so that it can be renamed over it atomically. The
+    // name is fixed, as the tag comes from the network and could point anywhere.
+    let exe = std::env::current_exe()?;
+    let download = exe.with_file_name(".foldity.download");
+
+    println!(
+        "Downloading {} from {}",
+        release.tag_name, asset.browser_download_url
+    );
+    let status = curl(&asset.browser_download_url, 600)
+        .arg("--output")
+        .arg(&download)
+        .status()?;
+    if !status.success() {
+        let _ = std::fs::remove_file(&download);
+        return Err(Error::UpdateDownload(status.to_string()).into());
+    }
+    if sha256sum(&download)? != Some(checksum) {
+        let _ = std::fs::remove_file(&download);
+        return Err(Error::UpdateChecksum(name).into());
+    }
+
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&download, std::fs::Permissions::from_mode(0o755))?;
+    }
+    std::fs::rename(&download, &exe)?;
+
+    println!(
+        "Updated foldity from {} to {}",
+        env!("CARGO_PKG_VERSION"),
+        release.tag_name
+    );
+
+    Ok(())
+}
+
+/// Mention a newer release on stderr, if there is one. Being only a courtesy, this gives up
+/// quickly and quietly.
+pub fn notify_newer() {
+    let release = match latest_release(3) {
+        Ok(release) => release,
+        Err(_) => return,
+    };
+
+    if release.version() > current_version() {
+        eprintln!(
+            "foldity {} is available (running {}), update with: foldity self-update",
+            release.tag_name,
+            env!("CARGO_PKG_VERSION")
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checksum_lines() {
+        let digest = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+        let line = format!("{}  foldity-x86_64-linux\n", digest);
+        assert_eq!(parse_checksum(&line).as_deref(), Some(digest));
+        assert_eq!(
+            parse_checksum(&digest.to_uppercase()).as_deref(),
+            Some(digest)
+        );
+        assert_eq!(parse_checksum(""), None);
+        assert_eq!(parse_checksum("9f86d081  foldity"), None);
+        assert_eq!(parse_checksum(&digest.replace('9', "g")), None);
+    }
+
+    #[test]
+    fn versions() {
+        assert_eq!(parse_version("v1.2.3"), vec![1, 2, 3]);
+        assert!(parse_version("1.10.0") > parse_version("1.9.9"));
+    }
+}
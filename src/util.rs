@@ -1,3 +1,8 @@
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
 /// Return the number of items to assign to a slot `idx` out of `0..n - 1`, if we want to divide
 /// `a` items as equal as possible to `n` slots.
 pub fn most_equal_divide(a: u64, n: u64, idx: u64) -> u64 {
@@ -7,3 +12,442 @@ pub fn most_equal_divide(a: u64, n: u64, idx: u64) -> u64 {
     }
     d
 }
+
+/// Divide `a` between parts in proportion to their weights, never giving a part more than it
+/// needs. What a part does not need goes to the others. Parts with no weight get nothing.
+pub fn weighted_divide(a: u64, needs: &[u64], weights: &[u64]) -> Vec<u64> {
+    let mut shares = vec![0; needs.len()];
+    let mut open: Vec<usize> = (0..needs.len()).filter(|idx| weights[*idx] > 0).collect();
+    let mut left = a;
+
+    while !open.is_empty() {
+        let total: u64 = open.iter().map(|idx| weights[*idx]).sum();
+
+        // Parts that need less than their share are settled first, leaving more for the rest.
+        let settled: Vec<usize> = open
+            .iter()
+            .copied()
+            .filter(|idx| needs[*idx] <= left * weights[*idx] / total)
+            .collect();
+        if settled.is_empty() {
+            let mut given = 0;
+            for idx in &open {
+                shares[*idx] = left * weights[*idx] / total;
+                given += shares[*idx];
+            }
+            for idx in open.iter().cycle().take((left - given) as usize) {
+                shares[*idx] += 1;
+            }
+            break;
+        }
+
+        for idx in settled {
+            shares[idx] = needs[idx];
+            left -= needs[idx];
+            open.retain(|open| *open != idx);
+        }
+    }
+
+    shares
+}
+
+/// Parse a duration such as `2s`, `500ms`, `1.5m` or `1h`. A bare number is in seconds.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let invalid = || format!("invalid duration {}, expected e.g. 2s, 500ms, 1m or 1h", s);
+
+    let split = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number: f64 = number.parse().map_err(|_| invalid())?;
+
+    let secs = match unit {
+        "ms" => number / 1000.0,
+        "" | "s" => number,
+        "m" => number * 60.0,
+        "h" => number * 3600.0,
+        _ => return Err(invalid()),
+    };
+
+    Duration::try_from_secs_f64(secs).map_err(|_| invalid())
+}
+
+/// How bytes that are not valid UTF-8 are taken into text.
+#[derive(Clone, Copy, Debug)]
+pub enum InvalidUtf8 {
+    /// With the U+FFFD replacement character.
+    Replace,
+    /// As `\xNN` escapes, keeping the original bytes apparent.
+    Escape,
+}
+
+impl FromStr for InvalidUtf8 {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "replace" => Ok(InvalidUtf8::Replace),
+            "escape" => Ok(InvalidUtf8::Escape),
+            _ => Err(format!(
+                "unknown mode {}, expected one of: replace, escape",
+                s
+            )),
+        }
+    }
+}
+
+impl InvalidUtf8 {
+    pub fn decode(self, bytes: &[u8]) -> String {
+        if let Ok(s) = std::str::from_utf8(bytes) {
+            return s.to_owned();
+        }
+
+        match self {
+            InvalidUtf8::Replace => String::from_utf8_lossy(bytes).into_owned(),
+            InvalidUtf8::Escape => {
+                let mut s = String::with_capacity(bytes.len());
+                let mut rest = bytes;
+
+                loop {
+                    match std::str::from_utf8(rest) {
+                        Ok(valid) => {
+                            s.push_str(valid);
+                            return s;
+                        }
+                        Err(err) => {
+                            let (valid, invalid) = rest.split_at(err.valid_up_to());
+                            s.push_str(std::str::from_utf8(valid).unwrap());
+                            let len = err.error_len().unwrap_or(invalid.len());
+                            for byte in &invalid[..len] {
+                                s.push_str(&format!("\\x{:02x}", byte));
+                            }
+                            rest = &invalid[len..];
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The line as a terminal would show it: without a trailing carriage return, and otherwise only
+/// what follows the last one, as that overwrote the rest.
+pub fn normalize_eol(line: &str) -> &str {
+    let line = line.strip_suffix('\r').unwrap_or(line);
+    match line.rfind('\r') {
+        Some(pos) => &line[pos + 1..],
+        None => line,
+    }
+}
+
+/// Remove terminal escape sequences and other control characters, keeping tabs.
+pub fn strip_control(line: &str) -> String {
+    lazy_static! {
+        static ref ESCAPES: Regex =
+            Regex::new(r"\x1b(\[[0-?]*[ -/]*[@-~]|\][^\x07\x1b]*(\x07|\x1b\\)|[@-_])").unwrap();
+    }
+
+    ESCAPES
+        .replace_all(line, "")
+        .chars()
+        .filter(|c| *c == '\t' || !c.is_control())
+        .collect()
+}
+
+/// How points in time are shown.
+#[derive(Default, Clone, Copy, PartialEq, Debug)]
+pub enum Clock {
+    /// Relative to the start of the session, e.g. `+1m 02s`.
+    #[default]
+    Elapsed,
+    /// Local time on a 24-hour clock, e.g. `14:03:09`.
+    H24,
+    /// Local time on a 12-hour clock, e.g. `2:03:09 PM`.
+    H12,
+}
+
+impl FromStr for Clock {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "elapsed" => Clock::Elapsed,
+            "24h" => Clock::H24,
+            "12h" => Clock::H12,
+            _ => {
+                return Err(format!(
+                    "unknown time format {}, expected one of: elapsed, 24h, 12h",
+                    s
+                ))
+            }
+        })
+    }
+}
+
+/// Writes counts, sizes, durations and timestamps the same way wherever they are shown.
+#[derive(Clone, Copy, Debug)]
+pub struct Format {
+    pub clock: Clock,
+    /// Put between groups of thousands.
+    pub separator: char,
+    /// Put before the fractional part of a number.
+    pub decimal: char,
+}
+
+impl Default for Format {
+    fn default() -> Self {
+        Format {
+            clock: Clock::default(),
+            separator: ',',
+            decimal: '.',
+        }
+    }
+}
+
+impl Format {
+    /// Number punctuation following the locale of the environment.
+    pub fn from_locale(clock: Clock) -> Self {
+        let (separator, decimal) = locale_punctuation();
+        Format {
+            clock,
+            separator,
+            decimal,
+        }
+    }
+
+    /// A count with thousands separators, e.g. `1,234,567`.
+    pub fn count(&self, n: u64) -> String {
+        let digits = n.to_string();
+        let mut s = String::with_capacity(digits.len() + digits.len() / 3);
+
+        for (idx, c) in digits.chars().enumerate() {
+            if idx > 0 && (digits.len() - idx).is_multiple_of(3) {
+                s.push(self.separator);
+            }
+            s.push(c);
+        }
+
+        s
+    }
+
+    /// An amount of bytes in binary units, e.g. `1.5 KiB`.
+    pub fn bytes(&self, n: u64) -> String {
+        const UNITS: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+
+        if n < 1024 {
+            return format!("{} B", n);
+        }
+
+        let mut value = n as f64 / 1024.0;
+        let mut unit = 0;
+        while value >= 1024.0 && unit + 1 < UNITS.len() {
+            value /= 1024.0;
+            unit += 1;
+        }
+
+        format!("{} {}", self.fraction(value), UNITS[unit])
+    }
+
+    /// A duration in the largest units that apply, e.g. `1h 02m 03s`, `2m 03s`, `3.2s`, or
+    /// `450ms`.
+    pub fn duration(&self, d: Duration) -> String {
+        let secs = d.as_secs();
+        // What would round up to a minute in tenths of seconds is shown as one.
+        let tenths = (d.as_millis() + 50) / 100;
+
+        if secs >= 3600 {
+            format!("{}h {:02}m {:02}s", secs / 3600, secs / 60 % 60, secs % 60)
+        } else if tenths >= 600 {
+            let secs = secs.max(60);
+            format!("{}m {:02}s", secs / 60, secs % 60)
+        } else if secs >= 1 {
+            format!("{}s", self.fraction(d.as_secs_f64()))
+        } else {
+            format!("{}ms", d.as_millis())
+        }
+    }
+
+    /// An amount per second, e.g. `12.5 lines/s`.
+    pub fn rate(&self, per_sec: f64, unit: &str) -> String {
+        format!("{} {}/s", self.fraction(per_sec), unit)
+    }
+
+    /// The time at `at` into a session that started at `origin`.
+    pub fn timestamp(&self, origin: SystemTime, at: Duration) -> String {
+        let (hour, minute, second) = match self.clock {
+            Clock::Elapsed => return format!("+{}", self.duration(at)),
+            Clock::H24 | Clock::H12 => local_time(origin + at),
+        };
+
+        match self.clock {
+            Clock::H12 => format!(
+                "{}:{:02}:{:02} {}",
+                (hour + 11) % 12 + 1,
+                minute,
+                second,
+                if hour < 12 { "AM" } else { "PM" }
+            ),
+            _ => format!("{:02}:{:02}:{:02}", hour, minute, second),
+        }
+    }
+
+    /// A number with a single decimal digit.
+    fn fraction(&self, value: f64) -> String {
+        let s = format!("{:.1}", value);
+        if self.decimal == '.' {
+            s
+        } else {
+            s.replace('.', &self.decimal.to_string())
+        }
+    }
+}
+
+/// Hour, minute and second of the given time in the local time zone.
+fn local_time(time: SystemTime) -> (i32, i32, i32) {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs()) as libc::time_t;
+
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    unsafe {
+        libc::localtime_r(&secs, &mut tm);
+    }
+
+    (tm.tm_hour, tm.tm_min, tm.tm_sec)
+}
+
+/// Thousands separator and decimal mark of the numeric locale, going by its language.
+fn locale_punctuation() -> (char, char) {
+    for var in &["LC_ALL", "LC_NUMERIC", "LANG"] {
+        if let Ok(value) = std::env::var(var) {
+            if !value.is_empty() {
+                let language = value.split(['_', '.']).next().unwrap_or("");
+                return match language {
+                    "de" | "nl" | "it" | "es" | "pt" | "da" | "id" | "tr" | "el" => ('.', ','),
+                    "fr" | "ru" | "pl" | "cs" | "sk" | "sv" | "fi" | "nb" | "uk" | "hu" => {
+                        (' ', ',')
+                    }
+                    _ => (',', '.'),
+                };
+            }
+        }
+    }
+
+    (',', '.')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn weighted_divide_by_weight() {
+        assert_eq!(weighted_divide(8, &[10, 10], &[3, 1]), vec![6, 2]);
+        assert_eq!(weighted_divide(9, &[10, 10, 10], &[1, 1, 1]), vec![3, 3, 3]);
+    }
+
+    #[test]
+    fn weighted_divide_passes_on_what_is_not_needed() {
+        assert_eq!(weighted_divide(10, &[2, 20], &[3, 1]), vec![2, 8]);
+        assert_eq!(weighted_divide(10, &[1, 1], &[1, 1]), vec![1, 1]);
+    }
+
+    #[test]
+    fn weighted_divide_gives_out_everything() {
+        for a in 0..30 {
+            let shares = weighted_divide(a, &[7, 9, 11], &[3, 1, 2]);
+            assert_eq!(shares.iter().sum::<u64>(), a.min(27), "{}", a);
+            assert!(shares.iter().zip([7, 9, 11]).all(|(s, n)| *s <= n));
+        }
+    }
+
+    #[test]
+    fn weighted_divide_skips_weightless() {
+        assert_eq!(weighted_divide(5, &[5, 5], &[0, 1]), vec![0, 5]);
+    }
+
+    #[test]
+    fn most_equal() {
+        let shares: Vec<u64> = (0..3).map(|idx| most_equal_divide(7, 3, idx)).collect();
+        assert_eq!(shares, vec![3, 2, 2]);
+    }
+
+    #[test]
+    fn parse_duration_units() {
+        assert_eq!(parse_duration("2"), Ok(Duration::from_secs(2)));
+        assert_eq!(parse_duration("2s"), Ok(Duration::from_secs(2)));
+        assert_eq!(parse_duration("500ms"), Ok(Duration::from_millis(500)));
+        assert_eq!(parse_duration("1.5m"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("1h"), Ok(Duration::from_secs(3600)));
+    }
+
+    #[test]
+    fn parse_duration_rejects_garbage() {
+        for s in ["", "s", "2d", "1.2.3s", "-1s", "1e300s", "ms5"] {
+            assert!(parse_duration(s).is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn parse_duration_rejects_overflow() {
+        let huge = format!("{}h", "9".repeat(40));
+        assert!(parse_duration(&huge).is_err());
+    }
+
+    #[test]
+    fn counts() {
+        let format = Format::default();
+        assert_eq!(format.count(0), "0");
+        assert_eq!(format.count(999), "999");
+        assert_eq!(format.count(1000), "1,000");
+        assert_eq!(format.count(1234567), "1,234,567");
+
+        let format = Format {
+            separator: '.',
+            ..Format::default()
+        };
+        assert_eq!(format.count(123456), "123.456");
+    }
+
+    #[test]
+    fn bytes() {
+        let format = Format::default();
+        assert_eq!(format.bytes(1023), "1023 B");
+        assert_eq!(format.bytes(1536), "1.5 KiB");
+        assert_eq!(format.bytes(3 << 30), "3.0 GiB");
+
+        let format = Format {
+            decimal: ',',
+            ..Format::default()
+        };
+        assert_eq!(format.bytes(1536), "1,5 KiB");
+    }
+
+    #[test]
+    fn durations() {
+        let format = Format::default();
+        assert_eq!(format.duration(Duration::from_millis(450)), "450ms");
+        assert_eq!(format.duration(Duration::from_millis(3200)), "3.2s");
+        assert_eq!(format.duration(Duration::from_secs(123)), "2m 03s");
+        assert_eq!(format.duration(Duration::from_millis(59940)), "59.9s");
+        assert_eq!(format.duration(Duration::from_millis(59950)), "1m 00s");
+        assert_eq!(format.duration(Duration::from_secs(60)), "1m 00s");
+        assert_eq!(format.duration(Duration::from_millis(999)), "999ms");
+        assert_eq!(format.duration(Duration::from_secs(3723)), "1h 02m 03s");
+        assert_eq!(format.rate(12.5, "lines"), "12.5 lines/s");
+    }
+
+    #[test]
+    fn elapsed_timestamps() {
+        let format = Format::default();
+        let at = Duration::from_secs(62);
+        assert_eq!(format.timestamp(UNIX_EPOCH, at), "+1m 02s");
+    }
+
+    #[test]
+    fn clocks() {
+        assert_eq!("24h".parse(), Ok(Clock::H24));
+        assert_eq!("12h".parse(), Ok(Clock::H12));
+        assert!("13h".parse::<Clock>().is_err());
+    }
+}
@@ -0,0 +1,136 @@
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+use std::time::{Duration, Instant};
+
+/// Run foldity with its output piped, failing if it does not exit on its own.
+fn run_piped(args: &[&str]) -> Output {
+    run_with_input(args, "")
+}
+
+/// Like `run_piped`, with the given input.
+fn run_with_input(args: &[&str], input: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_foldity"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while child.try_wait().unwrap().is_none() {
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            panic!("foldity {:?} did not exit", args);
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+
+    child.wait_with_output().unwrap()
+}
+
+#[test]
+fn exits_when_piped() {
+    let output = run_piped(&["--", "sh", "-c", "echo a; echo b"]);
+    assert!(output.status.success());
+}
+
+#[test]
+fn exits_when_debugging() {
+    let output = run_piped(&["--debug", "--", "sh", "-c", "echo a; echo b"]);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "Line: a\nLine: b\n"
+    );
+}
+
+#[test]
+fn reports_mirror_failures() {
+    let output = run_piped(&["--mirror", "unix:/nonexistent/x/sock", "--", "true"]);
+    assert!(!output.status.success());
+    assert!(!output.stderr.is_empty());
+}
+
+#[test]
+fn mirror_keeps_other_files() {
+    let path = std::env::temp_dir().join(format!("foldity-mirror-{}", std::process::id()));
+    std::fs::write(&path, "keep").unwrap();
+
+    let address = format!("unix:{}", path.display());
+    let output = run_piped(&["--mirror", &address, "--", "true"]);
+    let kept = std::fs::read_to_string(&path);
+    let _ = std::fs::remove_file(&path);
+
+    assert!(!output.status.success());
+    assert_eq!(kept.unwrap(), "keep");
+}
+
+#[test]
+fn spools_all_lines() {
+    let dir = std::env::temp_dir().join(format!("foldity-spool-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+
+    let script = "echo a; sleep 0.2; echo b; echo c";
+    let output = run_piped(&["--spool", dir.to_str().unwrap(), "--", "sh", "-c", script]);
+    let snapshot = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .max()
+        .map(std::fs::read_to_string);
+    let _ = std::fs::remove_dir_all(&dir);
+
+    assert!(output.status.success());
+    let snapshot: serde_json::Value = serde_json::from_str(&snapshot.unwrap().unwrap()).unwrap();
+    let lines: Vec<_> = snapshot["programs"][0]["lines"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|line| line["text"].as_str().unwrap())
+        .collect();
+    assert_eq!(lines, ["a", "b", "c"]);
+    assert!(snapshot["programs"][0]["outcome"].is_object());
+}
+
+#[test]
+fn reports_bad_programs() {
+    let input = "#@ bogus\necho a\n#@ nice: x\necho b\necho c\n";
+    let output = run_with_input(&["--debug", "--programs-file", "-"], input);
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    for line in ["Line: a", "Line: b", "Line: c"] {
+        assert!(stdout.contains(line), "{} not in {}", line, stdout);
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(stderr.matches("warning:").count(), 2, "{}", stderr);
+}
+
+#[test]
+fn reports_programs_failing_to_start() {
+    let args = [
+        "--debug",
+        "--stagger",
+        "100ms",
+        "echo",
+        "a",
+        "-/-",
+        "/nonexistent/x",
+        "-/-",
+        "echo",
+        "b",
+    ];
+    let output = run_piped(&args);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "Line: a\nLine: b\n"
+    );
+    assert!(String::from_utf8_lossy(&output.stderr).contains("warning: /nonexistent/x: "));
+}